@@ -1,18 +1,20 @@
 import sys
+import json
 import zmq
 import os
 
-id = 'C' + str(os.getpid())
-print('ID:', id)
+client_id = 'C' + str(os.getpid())
+print('ID:', client_id)
 
 context = zmq.Context()
 req = context.socket(zmq.REQ)
-req.identity = id.encode('ascii')
+req.identity = client_id.encode('ascii')
 req.setsockopt(zmq.SNDTIMEO, 10000)
 req.setsockopt(zmq.RCVTIMEO, 10000)
 req.connect('tcp://127.0.0.1:6660')
 
 msg = """{
+    "version": 1,
     "url": "file:///Users/leandro/Projects/rust/wkhtmltopdf-cluster/examples/client/sample1.html",
     "global": {
         "documentTitle": "WkHTMLtoPDF Cluster :: Example Client",
@@ -26,7 +28,8 @@ msg = """{
     "onWarning": {
         "action": "abort",
         "triggerWords": ["error", "fail"]
-    }
+    },
+    "output": "both"
 }"""
 
 if len(sys.argv) > 1:
@@ -39,7 +42,20 @@ for i in range(1, 2):
         print('REQ:', msg)
 
         resp = req.recv_multipart()
-        print('RESP:', resp)
+        print('RESP:', resp[:4])
+        print('REPLY:', json.loads(resp[4]))
+
+        # WORKER, EMPTY, REPLY, EMPTY, CONTENT, PDF
+        if len(resp) > 5:
+            filename = client_id + '.pdf'
+            with open(filename, 'wb') as f:
+                f.write(resp[5])
+            print('PDF:', filename, len(resp[5]), 'bytes')
+    except zmq.Again as e:
+        # no reply in time, worth trying again later (EX_TEMPFAIL)
+        print(e)
+        sys.exit(75)
     except Exception as e:
+        # EX_SOFTWARE
         print(e)
-        quit(666)
+        sys.exit(70)
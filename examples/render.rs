@@ -0,0 +1,36 @@
+use std::env;
+use std::fs;
+use std::process;
+use wkhtmltopdf_cluster::client::ClusterClient;
+use wkhtmltopdf_cluster::protocol::{OutputMode, RenderRequest};
+
+// $ cargo run --example render -- file:///path/to/sample1.html sample1.pdf
+//
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: render <URL> <PDF>");
+        process::exit(1);
+    }
+
+    let mut request = RenderRequest::new(&args[1]);
+    request.output = OutputMode::Content;
+
+    let mut client = ClusterClient::default();
+    match client.render(&request) {
+        Ok(rendered) => {
+            let pdf = rendered.pdf.unwrap_or_default();
+            fs::write(&args[2], &pdf).expect("failed to write PDF");
+            println!(
+                "Worker #{} rendered {} bytes",
+                rendered.worker_id,
+                pdf.len()
+            );
+        }
+        Err(reason) => {
+            eprintln!("failed to render {}: {}", args[1], reason);
+            process::exit(1);
+        }
+    }
+}
@@ -1,14 +1,28 @@
-use clap::{App, Arg};
-use ctrlc;
+use clap::{App, Arg, ArgMatches};
+use signal_hook;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::env;
+use std::fmt::Display;
 use std::path::Path;
 use std::process;
+use std::str::FromStr;
 use std::time::Duration;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use wkhtmltopdf_cluster::broker::Broker;
+use std::thread;
+use wkhtmltopdf_cluster::broker::{Broker, BrokerConfig};
+use wkhtmltopdf_cluster::error::{exit_with_error, AnyError, ErrorKind};
+use wkhtmltopdf_cluster::protocol::{
+    heartbeat_interval, heartbeat_liveness, DEFAULT_BACKEND_ENDPOINT, DEFAULT_FRONTEND_ENDPOINT,
+};
+use wkhtmltopdf_cluster::quota::Quota;
+use wkhtmltopdf_cluster::scaling::ScalingPolicy;
 
 // $ cargo run -p wkhtmltopdf-cluster --bin broker start -i 2
+// $ cargo run -p wkhtmltopdf-cluster --bin broker start -i 2 -f tcp://*:7660 -b ipc:///tmp/wk-backend
+// $ cargo run -p wkhtmltopdf-cluster --bin broker start -i 2 --min-instances 1 --max-instances 8
+// $ cargo run -p wkhtmltopdf-cluster --bin broker start -i 2 --drain-timeout 60
 //
 
 fn main() {
@@ -19,6 +33,24 @@ fn main() {
         .subcommand(
             App::new("start")
                 .about("Starts the cluster manager for a number of worker nodes")
+                .arg(
+                    Arg::with_name("frontend")
+                        .about("endpoint to bind for clients")
+                        .short('f')
+                        .long("frontend")
+                        .takes_value(true)
+                        .value_name("ENDPOINT")
+                        .default_value(DEFAULT_FRONTEND_ENDPOINT),
+                )
+                .arg(
+                    Arg::with_name("backend")
+                        .about("endpoint to bind for workers")
+                        .short('b')
+                        .long("backend")
+                        .takes_value(true)
+                        .value_name("ENDPOINT")
+                        .default_value(DEFAULT_BACKEND_ENDPOINT),
+                )
                 .arg(
                     Arg::with_name("instances")
                         .about("number of workers")
@@ -28,6 +60,20 @@ fn main() {
                         .value_name("NUMBER")
                         .required(true),
                 )
+                .arg(
+                    Arg::with_name("min-instances")
+                        .about("min number of workers, defaults to instances")
+                        .long("min-instances")
+                        .takes_value(true)
+                        .value_name("NUMBER"),
+                )
+                .arg(
+                    Arg::with_name("max-instances")
+                        .about("max number of workers, defaults to instances")
+                        .long("max-instances")
+                        .takes_value(true)
+                        .value_name("NUMBER"),
+                )
                 .arg(
                     Arg::with_name("worker")
                         .about("worker node's binary path")
@@ -48,12 +94,134 @@ fn main() {
                 )
                 .arg(
                     Arg::with_name("timeout")
-                        .about("max seconds per request")
+                        .about("max seconds per request, unless it tells its timeoutMs")
                         .short('t')
                         .long("timeout")
                         .takes_value(true)
                         .value_name("TIMEOUT")
                         .default_value("5"),
+                )
+                .arg(
+                    Arg::with_name("heartbeat")
+                        .about("milliseconds between heartbeats with workers")
+                        .long("heartbeat")
+                        .takes_value(true)
+                        .value_name("MS")
+                        .default_value("1000"),
+                )
+                .arg(
+                    Arg::with_name("liveness")
+                        .about("missed heartbeats before a worker is given up on")
+                        .long("liveness")
+                        .takes_value(true)
+                        .value_name("NUMBER")
+                        .default_value("3"),
+                )
+                .arg(
+                    Arg::with_name("retries")
+                        .about("times a request of a lost worker goes to another one")
+                        .short('r')
+                        .long("retries")
+                        .takes_value(true)
+                        .value_name("NUMBER")
+                        .default_value("2"),
+                )
+                .arg(
+                    Arg::with_name("queue-depth")
+                        .about("max requests waiting for a worker")
+                        .long("queue-depth")
+                        .takes_value(true)
+                        .value_name("NUMBER")
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::with_name("queue-deadline")
+                        .about("max seconds a request waits for a worker")
+                        .long("queue-deadline")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::with_name("max-concurrency")
+                        .about("max requests in progress, shared by tenants without --quota")
+                        .long("max-concurrency")
+                        .takes_value(true)
+                        .value_name("NUMBER")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("max-rpm")
+                        .about("max requests per minute, shared by tenants without --quota")
+                        .long("max-rpm")
+                        .takes_value(true)
+                        .value_name("NUMBER")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("quota")
+                        .about("quota of a given tenant, overriding the ones above")
+                        .long("quota")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("TENANT=CONCURRENCY/RPM"),
+                )
+                .arg(
+                    Arg::with_name("max-requests")
+                        .about("renders before a worker is recycled, 0 for never")
+                        .long("max-requests")
+                        .takes_value(true)
+                        .value_name("NUMBER")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("max-rss")
+                        .about("memory in kB above which a worker is recycled, 0 for unlimited")
+                        .long("max-rss")
+                        .takes_value(true)
+                        .value_name("KB")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("drain-timeout")
+                        .about("max seconds renders in progress have to finish on shutdown")
+                        .long("drain-timeout")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .default_value("30"),
+                )
+                .arg(
+                    Arg::with_name("max-request-size")
+                        .about("max bytes of a request, above which it is refused")
+                        .long("max-request-size")
+                        .takes_value(true)
+                        .value_name("BYTES")
+                        .default_value("33554432"),
+                )
+                .arg(
+                    Arg::with_name("job-ttl")
+                        .about("seconds results of finished jobs are kept to be fetched")
+                        .long("job-ttl")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .default_value("600"),
+                )
+                .arg(
+                    Arg::with_name("max-jobs")
+                        .about("max results of finished jobs kept, above which jobs are refused")
+                        .long("max-jobs")
+                        .takes_value(true)
+                        .value_name("NUMBER")
+                        .default_value("1000"),
+                )
+                .arg(
+                    Arg::with_name("max-job-bytes")
+                        .about("max bytes of finished jobs' results kept, as above")
+                        .long("max-job-bytes")
+                        .takes_value(true)
+                        .value_name("BYTES")
+                        .default_value("536870912"),
                 ),
         );
 
@@ -63,40 +231,94 @@ fn main() {
     let matches = app.get_matches_mut();
     match matches.subcommand() {
         ("start", Some(sub_matches)) => {
-            let w_instances: usize = sub_matches.value_of_t("instances").unwrap();
+            let w_instances = parse_arg::<usize>(&sub_matches, "instances");
+            let w_min_instances =
+                parse_optional_arg::<usize>(&sub_matches, "min-instances").unwrap_or(w_instances);
+            let w_max_instances = parse_optional_arg::<usize>(&sub_matches, "max-instances")
+                .unwrap_or_else(|| w_instances.max(w_min_instances));
+            if w_min_instances > w_max_instances {
+                exit_with_error(
+                    "failed to start broker",
+                    &AnyError::without_parent(
+                        ErrorKind::Config,
+                        "Min instances must not be greater than max instances",
+                    ),
+                );
+            }
             let w_binpath: String = sub_matches
                 .value_of_t("worker")
                 .unwrap_or_else(|_err| get_default_worker_path());
             let w_output: String = sub_matches
                 .value_of_t("output")
                 .unwrap_or_else(|_err| get_default_output_dir());
-            let w_timeout = Duration::from_secs(
-                sub_matches
-                    .value_of("timeout")
-                    .unwrap()
-                    .parse::<u64>()
-                    .expect("failed to parse timeout argument"),
-            );
+            let w_timeout = Duration::from_secs(parse_arg::<u64>(&sub_matches, "timeout"));
     
             let broker_id = process::id();
 
             println!("WkHTMLtoPDF Cluster :: Manager :: Start [#{}]", broker_id);
-            let mut broker = Broker::new(
-                broker_id,
-                stop_signal.clone(),
+            let mut config = BrokerConfig::new(
                 w_instances,
                 Path::new(&w_binpath),
                 Path::new(&w_output),
                 w_timeout
             );
-            broker
-                .run(|worker_pids| {
-                    println!("All workers are up & running:");
-                    for worker_pid in worker_pids {
-                        println!("- Worker PID: {}", worker_pid);
-                    }
-                })
-                .expect("failed on running broker");
+            config.scaling = ScalingPolicy::new(w_min_instances, w_max_instances);
+            config.frontend_endpoint = String::from(sub_matches.value_of("frontend").unwrap());
+            config.backend_endpoint = String::from(sub_matches.value_of("backend").unwrap());
+            config.heartbeat_interval =
+                heartbeat_interval(parse_arg::<u64>(&sub_matches, "heartbeat")).unwrap_or_else(
+                    |reason| exit_with_error("failed to parse heartbeat argument", &reason),
+                );
+            config.heartbeat_liveness =
+                heartbeat_liveness(parse_arg::<u32>(&sub_matches, "liveness")).unwrap_or_else(
+                    |reason| exit_with_error("failed to parse liveness argument", &reason),
+                );
+            config.max_retries = parse_arg::<u32>(&sub_matches, "retries");
+            config.queue_max_depth = parse_arg::<usize>(&sub_matches, "queue-depth");
+            config.queue_deadline =
+                Duration::from_secs(parse_arg::<u64>(&sub_matches, "queue-deadline"));
+            config.tenant_quota = Quota::new(
+                parse_arg::<usize>(&sub_matches, "max-concurrency"),
+                parse_arg::<usize>(&sub_matches, "max-rpm"),
+            );
+            let max_requests = parse_arg::<u32>(&sub_matches, "max-requests");
+            config.max_requests_per_worker = if max_requests == 0 {
+                None
+            } else {
+                Some(max_requests)
+            };
+            let max_rss_kb = parse_arg::<u64>(&sub_matches, "max-rss");
+            config.max_worker_rss_kb = if max_rss_kb == 0 {
+                None
+            } else {
+                Some(max_rss_kb)
+            };
+            config.drain_timeout =
+                Duration::from_secs(parse_arg::<u64>(&sub_matches, "drain-timeout"));
+            config.max_request_size = parse_arg::<usize>(&sub_matches, "max-request-size");
+            config.job_ttl = Duration::from_secs(parse_arg::<u64>(&sub_matches, "job-ttl"));
+            config.max_finished_jobs = parse_arg::<usize>(&sub_matches, "max-jobs");
+            config.max_finished_job_bytes = parse_arg::<usize>(&sub_matches, "max-job-bytes");
+            if let Some(specs) = sub_matches.values_of("quota") {
+                for spec in specs {
+                    let (tenant, quota) = Quota::parse_override(spec).unwrap_or_else(|reason| {
+                        exit_with_error("failed to parse quota argument", &reason)
+                    });
+                    config.tenant_quota_overrides.insert(tenant, quota);
+                }
+            }
+
+            let mut broker = Broker::new(broker_id, stop_signal.clone(), config);
+            watch_restart_signal(broker.restart_signal());
+            let result = broker.run(|worker_pids| {
+                println!("All workers are up & running:");
+                for worker_pid in worker_pids {
+                    println!("- Worker PID: {}", worker_pid);
+                }
+            });
+            if let Err(reason) = result {
+                exit_with_error("failed on running broker", &reason);
+            }
             println!("WkHTMLtoPDF Cluster :: Manager :: End [#{}]", broker_id);
             println!("Bye, bye!");
             process::exit(0);
@@ -106,17 +328,67 @@ fn main() {
     }
 }
 
+// Bad arguments are a config error, so the broker exits with its code.
+fn parse_arg<T: FromStr>(sub_matches: &ArgMatches, name: &str) -> T
+where
+    T::Err: Display,
+{
+    parse_value(name, sub_matches.value_of(name).unwrap_or_default())
+}
+
+// Arguments without a default are none unless given, but never wrong.
+fn parse_optional_arg<T: FromStr>(sub_matches: &ArgMatches, name: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    sub_matches
+        .value_of(name)
+        .map(|value| parse_value(name, value))
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> T
+where
+    T::Err: Display,
+{
+    value.parse::<T>().unwrap_or_else(|reason| {
+        exit_with_error(
+            format!("failed to parse {} argument", name).as_str(),
+            &AnyError::without_parent(
+                ErrorKind::Config,
+                format!("{:?} is not valid: {}", value, reason).as_str(),
+            ),
+        )
+    })
+}
+
 fn watch_stop_signal(stop_signal: Arc<AtomicBool>) {
-    ctrlc::set_handler(move || {
-        if !stop_signal.load(Ordering::SeqCst) {
-            println!("[Ctrl+C]\nShutting down...");
-            stop_signal.store(true, Ordering::SeqCst);           
-            return;
+    // Ctrl+C as much as e.g. `kill` or a service manager stopping the broker;
+    // spawned workers do not get either, since broker drives their shutdown
+    let mut signals =
+        Signals::new(&[SIGINT, SIGTERM]).expect("failed while setting stop signal handler");
+    let broker_id = process::id();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if !stop_signal.load(Ordering::SeqCst) {
+                println!(
+                    "[{}]\nShutting down...",
+                    signal_hook::low_level::signal_name(signal).unwrap_or("signal")
+                );
+                stop_signal.store(true, Ordering::SeqCst);
+                continue;
+            }
+            // asked twice, so not even spawned workers are waited for
+            Broker::kill_workers(broker_id);
+            println!("Bye, bye!");
+            process::exit(0);
         }
-        println!("Bye, bye!");
-        process::exit(0);
-    })
-    .expect("failed while setting Ctrl-C handler");
+    });
+}
+
+fn watch_restart_signal(restart_signal: Arc<AtomicBool>) {
+    // e.g. `kill -HUP <broker pid>` once a new worker binary is deployed
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, restart_signal)
+        .expect("failed while setting SIGHUP handler");
 }
 
 fn get_default_worker_path() -> String {
@@ -1,14 +1,21 @@
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::fmt::Display;
 use std::path::Path;
 use std::process;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
+use wkhtmltopdf_cluster::error::{exit_with_error, AnyError, ErrorKind};
 use wkhtmltopdf_cluster::helpers::fs_helpers::create_dir_if_not_exists;
+use wkhtmltopdf_cluster::protocol::{heartbeat_interval, DEFAULT_BACKEND_ENDPOINT};
 use wkhtmltopdf_cluster::worker::Worker;
 
 // $ cargo run -p wkhtmltopdf-cluster --bin worker start --output ./examples/pdf
+// $ cargo run -p wkhtmltopdf-cluster --bin worker start --connect tcp://10.0.0.5:6661 --output ./examples/pdf
 //
 
 fn main() {
@@ -19,6 +26,15 @@ fn main() {
         .subcommand(
             App::new("start")
                 .about("Starts the worker node for a given cluster")
+                .arg(
+                    Arg::with_name("connect")
+                        .about("broker's backend endpoint")
+                        .short('c')
+                        .long("connect")
+                        .takes_value(true)
+                        .value_name("ENDPOINT")
+                        .default_value(DEFAULT_BACKEND_ENDPOINT),
+                )
                 .arg(
                     Arg::with_name("output")
                         .about("output directory")
@@ -30,12 +46,20 @@ fn main() {
                 )
                 .arg(
                     Arg::with_name("timeout")
-                        .about("max seconds per request")
+                        .about("max seconds per request, unless it tells its timeoutMs")
                         .short('t')
                         .long("timeout")
                         .takes_value(true)
                         .value_name("TIMEOUT")
                         .default_value("5"),
+                )
+                .arg(
+                    Arg::with_name("heartbeat")
+                        .about("milliseconds between heartbeats with broker")
+                        .long("heartbeat")
+                        .takes_value(true)
+                        .value_name("MS")
+                        .default_value("1000"),
                 ),
         );
 
@@ -45,24 +69,33 @@ fn main() {
     let matches = app.get_matches_mut();
     match matches.subcommand() {
         ("start", Some(sub_matches)) => {
+            let broker_endpoint = sub_matches.value_of("connect").unwrap();
             let output_dir = Path::new(sub_matches.value_of("output").unwrap());
-            create_dir_if_not_exists(&output_dir).expect("failed to create directory");
+            if let Err(reason) = create_dir_if_not_exists(&output_dir) {
+                exit_with_error("failed to create output directory", &AnyError::from(reason));
+            }
 
-            let timeout = Duration::from_secs(
-                sub_matches
-                    .value_of("timeout")
-                    .unwrap()
-                    .parse::<u64>()
-                    .expect("failed to parse timeout argument"),
-            );
+            let timeout = Duration::from_secs(parse_arg::<u64>(&sub_matches, "timeout"));
+
+            let heartbeat_interval =
+                heartbeat_interval(parse_arg::<u64>(&sub_matches, "heartbeat")).unwrap_or_else(
+                    |reason| exit_with_error("failed to parse heartbeat argument", &reason),
+                );
 
             let worker_id = process::id();
 
             println!("WkHTMLtoPDF Cluster :: Worker :: Start [#{}]", worker_id);
-            let mut worker = Worker::new(worker_id, stop_signal.clone(), output_dir, timeout);
-            worker
-                .run(|| println!("- Worker #{} is ready", worker_id))
-                .expect("failed running worker");
+            let mut worker = Worker::new(
+                worker_id,
+                stop_signal.clone(),
+                broker_endpoint,
+                output_dir,
+                timeout,
+                heartbeat_interval,
+            );
+            if let Err(reason) = worker.run(|| println!("- Worker #{} is ready", worker_id)) {
+                exit_with_error("failed running worker", &reason);
+            }
             println!("WkHTMLtoPDF Cluster :: Worker :: End [#{}]", worker_id);
             process::exit(0);
         }
@@ -71,25 +104,42 @@ fn main() {
     }
 }
 
+// Bad arguments are a config error, so the worker exits with its code.
+fn parse_arg<T: FromStr>(sub_matches: &ArgMatches, name: &str) -> T
+where
+    T::Err: Display,
+{
+    let value = sub_matches.value_of(name).unwrap_or_default();
+    value.parse::<T>().unwrap_or_else(|reason| {
+        exit_with_error(
+            format!("failed to parse {} argument", name).as_str(),
+            &AnyError::without_parent(
+                ErrorKind::Config,
+                format!("{:?} is not valid: {}", value, reason).as_str(),
+            ),
+        )
+    })
+}
+
 fn watch_stop_signal(stop_signal: Arc<AtomicBool>) {
+    // a render in progress is finished and replied to before stopping, however
+    // long it takes, unless asked twice
+    let mut signals =
+        Signals::new(&[SIGINT, SIGTERM]).expect("failed while setting stop signal handler");
     let worker_id = process::id();
-    ctrlc::set_handler(move || {
-        if !stop_signal.load(Ordering::SeqCst) {
-            println!("[#{}] Worker got stop signal (Ctrl+C)", worker_id);
-            stop_signal.store(true, Ordering::SeqCst);
-
-            thread::spawn(move || {
-                // TODO: get this tolerance from user on start up
-                println!("[#{}] Will await for 5 secs max...", worker_id);
-                thread::sleep(Duration::from_secs(5));
-                println!("[#{}] Forced quit!", worker_id);
-                process::exit(666);
-            });
-
-            return;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if !stop_signal.load(Ordering::SeqCst) {
+                println!(
+                    "[#{}] Worker got stop signal ({})",
+                    worker_id,
+                    signal_hook::low_level::signal_name(signal).unwrap_or("signal")
+                );
+                stop_signal.store(true, Ordering::SeqCst);
+                continue;
+            }
+            println!("Worker #{} say au revoir", worker_id);
+            process::exit(0);
         }
-        println!("Worker #{} say au revoir", worker_id);
-        process::exit(0);
-    })
-    .expect("failed while setting Ctrl-C handler");
+    });
 }
@@ -1,64 +1,207 @@
-use super::error::{AnyError, Result};
-use super::helpers::zmq_helpers::{assert_empty, recv_string, send_multipart};
+use super::envelope::{
+    ClientCommand, ClientEnvelope, Malformed, ReplyTo, WorkerEnvelope, WorkerMessage,
+};
+use super::error::{error, ErrorKind, Result};
+use super::helpers::get_hostname;
+use super::helpers::zmq_helpers::{connect_endpoint, recv_multipart, send_multipart, Identity};
+use super::jobs::{JobResult, JobTable};
+use super::pool::{InFlight, WorkerPool};
 use super::protocol::*;
-use std::collections::HashMap;
-use std::collections::VecDeque;
+use super::queue::RequestQueue;
+use super::quota::{Quota, TenantQuotas};
+use super::scaling::{Load, ScalingPolicy};
+use std::collections::{HashMap, HashSet, VecDeque};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use sysinfo::{Process, ProcessExt, Signal, System, SystemExt};
 use zmq;
 
+// How many times a request is handed to another worker once the one rendering
+// it is lost, before its client is told so.
+pub const DEFAULT_MAX_RETRIES: u32 = 2;
+
+// How many requests may wait for a worker, and for how long, before clients
+// are told the cluster is unavailable.
+pub const DEFAULT_QUEUE_MAX_DEPTH: usize = 100;
+pub const DEFAULT_QUEUE_DEADLINE: Duration = Duration::from_secs(10);
+
+// How long renders in progress may take to finish once the broker is asked to
+// stop, before workers still around are killed.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How large a request may be, inline pages and assets included, before it is
+// refused rather than handed to a worker.
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 32 * 1024 * 1024;
+
+// How long results of finished jobs are kept for their clients to fetch them,
+// and how many of them, or how many bytes, before new jobs are refused.
+pub const DEFAULT_JOB_TTL: Duration = Duration::from_secs(600);
+pub const DEFAULT_MAX_FINISHED_JOBS: usize = 1000;
+pub const DEFAULT_MAX_FINISHED_JOB_BYTES: usize = 512 * 1024 * 1024;
+
 #[derive(Debug)]
 struct WorkerRef {
     pid: u32,
     os_process: Child,
+    retiring: bool,
+    rss_kb: u64,
 }
 
-#[derive(Debug)]
-pub struct Broker {
-    pub id: u32,
-    stop_signal: Arc<AtomicBool>,
+#[derive(Debug, Clone)]
+pub struct BrokerConfig {
+    pub frontend_endpoint: String,
+    pub backend_endpoint: String,
     pub worker_instances: usize,
     pub worker_binpath: PathBuf,
     pub worker_outpath: PathBuf,
     pub worker_timeout: Duration,
-    running_workers: Arc<RwLock<HashMap<u32, WorkerRef>>>,
+    pub heartbeat_interval: Duration,
+    pub heartbeat_liveness: u32,
+    pub max_retries: u32,
+    pub queue_max_depth: usize,
+    pub queue_deadline: Duration,
+    pub tenant_quota: Quota,
+    pub tenant_quota_overrides: HashMap<String, Quota>,
+    pub scaling: ScalingPolicy,
+    pub max_requests_per_worker: Option<u32>,
+    pub max_worker_rss_kb: Option<u64>,
+    pub drain_timeout: Duration,
+    pub max_request_size: usize,
+    pub job_ttl: Duration,
+    pub max_finished_jobs: usize,
+    pub max_finished_job_bytes: usize,
 }
 
-impl Broker {
+impl BrokerConfig {
     pub fn new(
-        id: u32,
-        stop_signal: Arc<AtomicBool>,
         worker_instances: usize,
         worker_binpath: &Path,
         worker_outpath: &Path,
         worker_timeout: Duration,
-    ) -> Broker {
-        let instance = Broker {
-            id: id,
-            stop_signal: stop_signal,
+    ) -> BrokerConfig {
+        let instance = BrokerConfig {
+            frontend_endpoint: String::from(DEFAULT_FRONTEND_ENDPOINT),
+            backend_endpoint: String::from(DEFAULT_BACKEND_ENDPOINT),
             worker_instances: worker_instances,
             worker_binpath: PathBuf::from(worker_binpath),
             worker_outpath: PathBuf::from(worker_outpath),
             worker_timeout: worker_timeout,
+            heartbeat_interval: HEARTBEAT_INTERVAL,
+            heartbeat_liveness: HEARTBEAT_LIVENESS,
+            max_retries: DEFAULT_MAX_RETRIES,
+            queue_max_depth: DEFAULT_QUEUE_MAX_DEPTH,
+            queue_deadline: DEFAULT_QUEUE_DEADLINE,
+            tenant_quota: Quota::default(),
+            tenant_quota_overrides: HashMap::new(),
+            scaling: ScalingPolicy::new(worker_instances, worker_instances),
+            max_requests_per_worker: None,
+            max_worker_rss_kb: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            job_ttl: DEFAULT_JOB_TTL,
+            max_finished_jobs: DEFAULT_MAX_FINISHED_JOBS,
+            max_finished_job_bytes: DEFAULT_MAX_FINISHED_JOB_BYTES,
+        };
+        instance
+    }
+}
+
+const MSG_CANNOT_ABORT_RENDER: &str =
+    "Request is rendering on a worker that cannot abort it, its reply will still come";
+
+// What became of a request asked to be cancelled.
+#[derive(Debug, PartialEq)]
+enum Cancellation {
+    Done,
+    // rendering on a worker the broker cannot make abort it
+    Refused,
+    Unknown,
+}
+
+// What the event loop keeps track of between messages.
+#[derive(Debug)]
+struct BrokerState {
+    worker_pool: WorkerPool,
+    retry_queue: VecDeque<InFlight>,
+    request_queue: RequestQueue,
+    tenant_quotas: TenantQuotas,
+    jobs: JobTable,
+    // workers told to STOP, until they answer GONE
+    stopping: HashMap<String, Instant>,
+    // spawned workers still to be replaced by a rolling restart
+    restarting: HashSet<u32>,
+    draining_since: Option<Instant>,
+    // envelopes dropped for not having the shape of any known one
+    malformed_envelopes: u64,
+}
+
+impl BrokerState {
+    fn new(config: &BrokerConfig) -> BrokerState {
+        let instance = BrokerState {
+            worker_pool: WorkerPool::new(config.heartbeat_interval * config.heartbeat_liveness),
+            retry_queue: VecDeque::new(),
+            request_queue: RequestQueue::new(config.queue_max_depth, config.queue_deadline),
+            tenant_quotas: TenantQuotas::new(
+                config.tenant_quota,
+                config.tenant_quota_overrides.clone(),
+            ),
+            jobs: JobTable::new(
+                config.job_ttl,
+                config.max_finished_jobs,
+                config.max_finished_job_bytes,
+            ),
+            stopping: HashMap::new(),
+            restarting: HashSet::new(),
+            draining_since: None,
+            malformed_envelopes: 0,
+        };
+        instance
+    }
+}
+
+#[derive(Debug)]
+pub struct Broker {
+    pub id: u32,
+    stop_signal: Arc<AtomicBool>,
+    restart_signal: Arc<AtomicBool>,
+    pub config: BrokerConfig,
+    running_workers: Arc<RwLock<HashMap<u32, WorkerRef>>>,
+}
+
+impl Broker {
+    pub fn new(id: u32, stop_signal: Arc<AtomicBool>, config: BrokerConfig) -> Broker {
+        let instance = Broker {
+            id: id,
+            stop_signal: stop_signal,
+            restart_signal: Arc::new(AtomicBool::new(false)),
+            config: config,
             running_workers: Arc::new(RwLock::new(HashMap::new())),
         };
         instance
     }
 
+    // Raising this flag makes the broker replace its spawned workers one at a
+    // time, e.g. after a new worker binary is deployed.
+    pub fn restart_signal(&self) -> Arc<AtomicBool> {
+        self.restart_signal.clone()
+    }
+
     pub fn run<F: Fn(Vec<u32>)>(&mut self, on_ready: F) -> Result<()> {
-        self.start(on_ready).expect("failed to start broker");
-        self.stop().expect("failed to stop broker");
-        Ok(())
+        // workers started before a failure are terminated all the same
+        let started = self.start(on_ready);
+        self.stop()?;
+        started
     }
 
     fn start<F: Fn(Vec<u32>)>(&mut self, on_ready: F) -> Result<()> {
-        self.start_workers().expect("failed to start workers");
-        self.watch_workers().expect("failed watching processess");
+        self.start_workers()?;
+        self.watch_workers()?;
         self.run_eventloop(|| {
             let pids = self
                 .running_workers
@@ -70,40 +213,42 @@ impl Broker {
             // workers are ready, so notify it
             on_ready(pids);
         })
-        .expect("failed to start proxy");
-        Ok(())
     }
 
     fn start_workers(&mut self) -> Result<()> {
-        for i in 0..self.worker_instances {
-            let child = Self::start_worker(
-                &self.worker_binpath,
-                &self.worker_outpath,
-                &self.worker_timeout,
-            )
-            .expect(format!("failed to start worker #{} {:?}", i, self.worker_binpath).as_str());
+        for _ in 0..self.config.worker_instances {
+            let child = Self::start_worker(&self.config)?;
             Self::register_worker(self.running_workers.clone(), child);
         }
         Ok(())
     }
 
-    fn start_worker(
-        worker_binpath: &PathBuf,
-        worker_outpath: &PathBuf,
-        worker_timeout: &Duration,
-    ) -> Result<Child> {
-        let result = Command::new(&worker_binpath)
+    fn start_worker(config: &BrokerConfig) -> Result<Child> {
+        let mut command = Command::new(&config.worker_binpath);
+        command
             .arg("start")
+            .arg("--connect")
+            .arg(connect_endpoint(&config.backend_endpoint))
             .arg("--output")
-            .arg(&worker_outpath.to_str().unwrap())
+            .arg(&config.worker_outpath.to_str().unwrap())
             .arg("--timeout")
-            .arg(worker_timeout.as_secs().to_string())
+            .arg(config.worker_timeout.as_secs().to_string())
+            .arg("--heartbeat")
+            .arg(config.heartbeat_interval.as_millis().to_string())
             .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .spawn();
+            .stderr(Stdio::inherit());
+        // a process group of its own keeps Ctrl+C on the terminal from reaching
+        // the worker, which is told to STOP by broker once drained instead
+        #[cfg(unix)]
+        command.process_group(0);
+        let result = command.spawn();
         match result {
             Ok(child) => Ok(child),
-            Err(reason) => Err(AnyError::from(reason)),
+            Err(reason) => error(
+                ErrorKind::Config,
+                format!("Cannot start worker {:?}", config.worker_binpath).as_str(),
+                reason,
+            ),
         }
     }
 
@@ -116,6 +261,8 @@ impl Broker {
                 WorkerRef {
                     pid: child.id(),
                     os_process: child,
+                    retiring: false,
+                    rss_kb: 0,
                 },
             );
     }
@@ -130,60 +277,181 @@ impl Broker {
     fn run_eventloop<F: Fn()>(&self, on_ready: F) -> Result<()> {
         let context = zmq::Context::new();
 
-        let frontend_socket = context.socket(zmq::ROUTER).unwrap();
-        frontend_socket
-            .bind("tcp://127.0.0.1:6660")
-            .expect("failed binding frontend socket");
+        // jobs are only known to the client that submitted them, so one that sets
+        // its identity keeps it when reconnecting, taking over the stale connection
+        let frontend_socket = context.socket(zmq::ROUTER)?;
+        frontend_socket.set_router_handover(true)?;
+        if let Err(reason) = frontend_socket.bind(&self.config.frontend_endpoint) {
+            return error(
+                ErrorKind::Config,
+                format!("Cannot bind frontend to {}", self.config.frontend_endpoint).as_str(),
+                reason,
+            );
+        }
 
-        let backend_socket = context.socket(zmq::ROUTER).unwrap();
-        backend_socket
-            .bind("tcp://127.0.0.1:6661")
-            .expect("failed binding backend socket");
+        // a worker reconnecting after a network hiccup keeps its identity, so it
+        // must take over its stale connection rather than being refused
+        let backend_socket = context.socket(zmq::ROUTER)?;
+        backend_socket.set_router_handover(true)?;
+        if let Err(reason) = backend_socket.bind(&self.config.backend_endpoint) {
+            return error(
+                ErrorKind::Config,
+                format!("Cannot bind backend to {}", self.config.backend_endpoint).as_str(),
+                reason,
+            );
+        }
 
         println!(
-            "Listening on:\n- Frontend: tcp://127.0.0.1:6660\n- Backend: tcp://127.0.0.1:6661"
+            "Listening on:\n- Frontend: {}\n- Backend: {}",
+            self.config.frontend_endpoint, self.config.backend_endpoint
         );
 
         // ready to start proxying, so notify it
         on_ready();
 
-        let mut available_workers = VecDeque::new();
+        let heartbeat_interval = self.config.heartbeat_interval;
+        let mut heartbeat_at = Instant::now() + heartbeat_interval;
+        let mut state = BrokerState::new(&self.config);
+
+        loop {
+            // stopping starts with draining workers, so renders in progress finish
+            if self.stop_signal.load(Ordering::SeqCst) {
+                match state.draining_since {
+                    None => self.start_draining(&backend_socket, &frontend_socket, &mut state),
+                    Some(since) => {
+                        if state.worker_pool.is_empty() && state.stopping.is_empty() {
+                            println!("All workers are drained");
+                            break;
+                        }
+                        if since.elapsed() >= self.config.drain_timeout {
+                            self.abort_draining(&frontend_socket, &mut state);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            // requests of lost workers go first, their clients have waited the longest
+            while state.worker_pool.has_available() {
+                match state.retry_queue.pop_front() {
+                    Some(in_flight) => dispatch_request(
+                        &backend_socket,
+                        &mut state,
+                        &in_flight.reply_to,
+                        &in_flight.request,
+                        &in_flight.tenant,
+                        in_flight.attempts + 1,
+                    ),
+                    None => break,
+                }
+            }
+
+            // then queued requests, unless they waited for too long already
+            for pending in state.request_queue.purge_expired(Instant::now()) {
+                state.tenant_quotas.release(&pending.tenant);
+                self.reply_rejected(
+                    &frontend_socket,
+                    &mut state.jobs,
+                    &pending.reply_to,
+                    REP_503_SERVICE_UNAVAILABLE,
+                    format!(
+                        "No worker available in {}s",
+                        self.config.queue_deadline.as_secs()
+                    )
+                    .as_str(),
+                );
+            }
+            while state.worker_pool.has_available() {
+                match state.request_queue.pop() {
+                    Some(pending) => dispatch_request(
+                        &backend_socket,
+                        &mut state,
+                        &pending.reply_to,
+                        &pending.request,
+                        &pending.tenant,
+                        1,
+                    ),
+                    None => break,
+                }
+            }
 
-        while !self.stop_signal.load(Ordering::SeqCst) {
+            // frontend is always polled, requests wait in the queue rather than in
+            // socket buffers, so clients get a reply even when cluster is overloaded
             let mut service_sockets = [
                 backend_socket.as_poll_item(zmq::POLLIN),
                 frontend_socket.as_poll_item(zmq::POLLIN),
             ];
-
-            // should only poll frontend if there is backend ready to work
-            let target_sockets = if available_workers.is_empty() { 1 } else { 2 };
-
-            // poll only current active sockets
-            let poll_result = zmq::poll(&mut service_sockets[0..target_sockets], 1000)
-                .expect("failed to poll sockets");
-            if poll_result == -1 {
-                println!("Will STOP due to error polling sockets");
-                self.stop_signal.store(true, Ordering::SeqCst);
+            // a signal (Ctrl+C, SIGHUP) interrupts polling, which is not restarted, so
+            // its flag is looked at right away instead; anything else means sockets
+            // are unusable, so the broker stops, terminating its workers all the same
+            match zmq::poll(&mut service_sockets, heartbeat_interval.as_millis() as i64) {
+                Ok(_) => {}
+                Err(zmq::Error::EINTR) => continue,
+                Err(reason) => return Err(reason.into()),
             }
 
+            // a peer whose messages cannot be read (e.g. gone halfway) is given up
+            // on, the broker keeps serving everyone else
             // -- backend
             if service_sockets[0].is_readable() {
-                self.handle_backend_talking(
-                    &backend_socket,
-                    &frontend_socket,
-                    &mut available_workers,
-                )
-                .expect("failed handling backend worker");
+                if let Err(reason) =
+                    self.handle_backend_talking(&backend_socket, &frontend_socket, &mut state)
+                {
+                    println!("Failed handling backend worker: {}", reason);
+                }
             }
 
             // -- frontend
             if service_sockets[1].is_readable() {
-                self.handle_frontend_talking(
-                    &backend_socket,
-                    &frontend_socket,
-                    &mut available_workers,
-                )
-                .expect("failed handling frontend client");
+                if let Err(reason) =
+                    self.handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+                {
+                    println!("Failed handling frontend client: {}", reason);
+                }
+            }
+
+            // -- house keeping
+            if Instant::now() >= heartbeat_at {
+                for worker_id in state.worker_pool.worker_ids() {
+                    send_worker_command(&backend_socket, &worker_id, MSG_HEARTBEAT);
+                }
+                heartbeat_at = Instant::now() + heartbeat_interval;
+
+                // load is looked at as often as heartbeats go out
+                if state.draining_since.is_none() {
+                    if self.restart_signal.swap(false, Ordering::SeqCst) {
+                        state.restarting = self.spawned_workers(&state).keys().cloned().collect();
+                        println!(
+                            "Will restart {} workers, one at a time",
+                            state.restarting.len()
+                        );
+                    }
+                    self.restart_workers(&backend_socket, &mut state);
+                    self.recycle_workers(&backend_socket, &mut state);
+                    self.autoscale(&backend_socket, &mut state);
+                }
+
+                let expired = state.jobs.purge_expired(Instant::now());
+                if expired > 0 {
+                    println!("Results of {} finished jobs expired", expired);
+                }
+                state.tenant_quotas.purge_idle(Instant::now());
+
+                // workers that never said GONE are not waited for any longer
+                let liveness = heartbeat_interval * self.config.heartbeat_liveness;
+                state
+                    .stopping
+                    .retain(|_, stopped_at| stopped_at.elapsed() < liveness);
+            }
+
+            for (worker_id, worker) in state.worker_pool.purge_expired(Instant::now()) {
+                println!(
+                    "Worker #{} missed its heartbeats and was removed",
+                    worker_id
+                );
+                if let Some(in_flight) = worker.in_flight {
+                    self.retry_or_fail(&frontend_socket, &mut state, &worker_id, in_flight);
+                }
             }
         }
 
@@ -195,81 +463,123 @@ impl Broker {
         &self,
         backend_socket: &zmq::Socket,
         frontend_socket: &zmq::Socket,
-        available_workers: &mut VecDeque<String>,
+        state: &mut BrokerState,
     ) -> Result<()> {
-        // worker envelope:
-        //   ID, EMPTY, READY
-        //   ID, EMPTY, GONE
-        //   ID, EMPTY, CLIENT, EMPTY, REPLY
-        //   ID, EMPTY, CLIENT, EMPTY, REPLY, EMPTY, CONTENT
-
-        let worker_id = recv_string(&backend_socket, "failed reading ID of worker's envelope");
-
-        assert_empty(
-            &backend_socket,
-            "failed reading 1st <EMPTY> of worker's envelope",
-        );
-
-        let worker_message = recv_string(
-            &backend_socket,
-            "failed reading <MESSAGE> of worker's envelope",
-        );
+        // whatever does not look like a worker envelope is dropped, the broker
+        // keeps serving everyone else
+        let frames = recv_multipart(&backend_socket)?;
+        let envelope = match WorkerEnvelope::parse(frames) {
+            Ok(envelope) => envelope,
+            Err(malformed) => {
+                self.discard_worker_envelope(&backend_socket, &frontend_socket, state, malformed);
+                return Ok(());
+            }
+        };
+        let worker_id = envelope.worker_id;
 
-        match worker_message.as_str() {
-            MSG_WORKER_IS_READY => {
-                available_workers.push_front(worker_id.clone());
-                println!("Worker #{} is ready", worker_id)
+        match envelope.message {
+            WorkerMessage::Ready(capabilities) => {
+                if state.draining_since.is_some() {
+                    println!("Worker #{} is ignored while draining", worker_id);
+                } else if capabilities.version != PROTOCOL_VERSION {
+                    println!(
+                        "Worker #{} speaks protocol version {} and will be stopped",
+                        worker_id, capabilities.version
+                    );
+                    send_worker_command(&backend_socket, &worker_id, MSG_STOP_WORKER);
+                } else {
+                    println!(
+                        "Worker #{} is ready at {} (PID {})",
+                        worker_id, capabilities.host, capabilities.pid
+                    );
+                    if let Some(in_flight) = state.worker_pool.register(&worker_id, capabilities) {
+                        println!(
+                            "Worker #{} is ready again before replying to client #{}",
+                            worker_id, in_flight.reply_to
+                        );
+                        self.retry_or_fail(&frontend_socket, state, &worker_id, in_flight);
+                    }
+                    // e.g. due for recycling, or its render cancelled, then reconnected
+                    if state.worker_pool.is_draining(&worker_id) {
+                        self.retire_worker(&backend_socket, state, &worker_id);
+                    }
+                }
             }
-            MSG_WORKER_IS_GONE => println!("Worker #{} is gone", worker_id),
-            client_id => {
-                assert_empty(
-                    &backend_socket,
-                    "failed reading 2nd <EMPTY> of worker's envelope",
-                );
-                let reply = recv_string(
-                    &backend_socket,
-                    "failed reading <REPLY> of worker's envelope",
-                );
-                assert_empty(
-                    &backend_socket,
-                    "failed reading 3nd <EMPTY> of worker's envelope",
-                );
-                let content = recv_string(
-                    &backend_socket,
-                    "failed reading <CONTENT> of worker's envelope",
-                );
+            WorkerMessage::Heartbeat => {
+                if !state.worker_pool.refresh(&worker_id) {
+                    println!(
+                        "Worker #{} is unknown, so its heartbeat is ignored",
+                        worker_id
+                    );
+                }
+            }
+            WorkerMessage::Gone => {
+                println!("Worker #{} is gone", worker_id);
+                state.stopping.remove(&worker_id);
+                if let Some(in_flight) = state
+                    .worker_pool
+                    .remove(&worker_id)
+                    .and_then(|w| w.in_flight)
+                {
+                    self.retry_or_fail(&frontend_socket, state, &worker_id, in_flight);
+                }
+            }
+            WorkerMessage::Reply {
+                client_id,
+                code: reply,
+                content,
+                attachments,
+            } => {
+                state.worker_pool.refresh(&worker_id);
                 println!(
-                    "Worker #{} send reply {} to client #{}: {}",
-                    worker_id, reply, client_id, content
+                    "Worker #{} send reply {} to client #{}: {} (+{} bytes attached)",
+                    worker_id,
+                    reply,
+                    client_id,
+                    String::from_utf8_lossy(&content),
+                    attachments.iter().map(|frame| frame.len()).sum::<usize>()
                 );
 
-                // multipart envelope from worker to client:
-                //   CLIENT, EMPTY, WORKER, EMPTY, REPLY, EMPTY, CONTENT
-                let reply_envelope = vec![
-                    client_id.as_bytes().to_vec(),
-                    b"".to_vec(),
-                    worker_id.as_bytes().to_vec(),
-                    b"".to_vec(),
-                    reply.as_bytes().to_vec(),
-                    b"".to_vec(),
-                    content.as_bytes().to_vec(),
-                ];
-
-                // forward reply envelope to given client
-                send_multipart(
+                // a reply nobody waits for anymore (e.g. client was already told the
+                // worker was lost) is dropped, and whatever the worker was meant to be
+                // busy with instead is not waited for any longer
+                let in_flight = match state.worker_pool.complete(&worker_id) {
+                    Some(in_flight) if in_flight.reply_to.client_id == client_id => in_flight,
+                    other => {
+                        println!(
+                            "Worker #{} replied to client #{} which does not expect it",
+                            worker_id, client_id
+                        );
+                        if let Some(in_flight) = other {
+                            self.retry_or_fail(&frontend_socket, state, &worker_id, in_flight);
+                        }
+                        if reply != REP_504_GATEWAY_TIMEOUT {
+                            state.worker_pool.release(&worker_id);
+                        }
+                        return Ok(());
+                    }
+                };
+
+                // a render that timed out is not retried, since it would likely time
+                // out again
+                state.tenant_quotas.release(&in_flight.tenant);
+
+                deliver_reply(
                     &frontend_socket,
-                    reply_envelope,
-                    format!(
-                        "failed forwarding reply from worker #{} to client #{}",
-                        worker_id, client_id
-                    )
-                    .as_str(),
+                    &mut state.jobs,
+                    &in_flight.reply_to,
+                    &worker_id,
+                    &reply,
+                    content,
+                    attachments,
                 );
 
-                if reply == REP_502_BAD_GATEWAY {
-                    println!("Worker #{} reply 502 and will panic", worker_id);
+                if reply == REP_504_GATEWAY_TIMEOUT {
+                    println!("Worker #{} reply 504 and will exit", worker_id);
+                } else if state.worker_pool.is_draining(&worker_id) {
+                    self.retire_worker(&backend_socket, state, &worker_id);
                 } else {
-                    available_workers.push_front(worker_id.clone());
+                    state.worker_pool.release(&worker_id);
                     println!("Worker #{} is available again", worker_id);
                 }
             }
@@ -281,58 +591,748 @@ impl Broker {
         &self,
         backend_socket: &zmq::Socket,
         frontend_socket: &zmq::Socket,
-        available_workers: &mut VecDeque<String>,
+        state: &mut BrokerState,
     ) -> Result<()> {
-        // client envelope:
-        //   ID, EMPTY, REQUEST
+        // clients that set no identity get a binary one from ZMQ, and requests
+        // are only parsed by workers, so neither is taken as text here
+        let frames = recv_multipart(&frontend_socket)?;
+        let envelope = match ClientEnvelope::parse(frames, self.config.max_request_size) {
+            Ok(envelope) => envelope,
+            Err(malformed) => {
+                self.discard_client_envelope(&frontend_socket, state, malformed);
+                return Ok(());
+            }
+        };
+        let client_id = envelope.client_id;
+        let request_id = envelope.request_id;
+        let (request, submitted) = match envelope.command {
+            ClientCommand::Render(request) => (request, false),
+            ClientCommand::Submit(request) => (request, true),
+            ClientCommand::Status(job_id) => {
+                self.reply_job_status(&frontend_socket, state, &client_id, &job_id);
+                return Ok(());
+            }
+            ClientCommand::Fetch(job_id) => {
+                self.reply_job_result(&frontend_socket, state, &client_id, &job_id);
+                return Ok(());
+            }
+            ClientCommand::Cancel => {
+                let reply_to = ReplyTo::request(&client_id, request_id.as_deref());
+                self.cancel_request(&backend_socket, &frontend_socket, state, &reply_to);
+                return Ok(());
+            }
+            ClientCommand::CancelJob(job_id) => {
+                self.cancel_job(
+                    &backend_socket,
+                    &frontend_socket,
+                    state,
+                    &client_id,
+                    &job_id,
+                );
+                return Ok(());
+            }
+        };
+
+        // requests refused right away are told so, even when submitted as jobs
+        let client = ReplyTo::request(&client_id, request_id.as_deref());
+        if state.draining_since.is_some() {
+            self.reply_rejected(
+                &frontend_socket,
+                &mut state.jobs,
+                &client,
+                REP_503_SERVICE_UNAVAILABLE,
+                "Broker is shutting down",
+            );
+            return Ok(());
+        }
+        if submitted && state.jobs.is_full() {
+            self.reply_rejected(
+                &frontend_socket,
+                &mut state.jobs,
+                &client,
+                REP_503_SERVICE_UNAVAILABLE,
+                "Too many job results waiting to be fetched",
+            );
+            return Ok(());
+        }
+
+        let header = RequestHeader::peek(&request);
+        if let Err(reason) = state.tenant_quotas.admit(header.tenant(), Instant::now()) {
+            self.reply_rejected(
+                &frontend_socket,
+                &mut state.jobs,
+                &client,
+                REP_429_TOO_MANY_REQUESTS,
+                reason.as_str(),
+            );
+            return Ok(());
+        }
+
+        let reply_to = match submitted {
+            true => ReplyTo::job(&client_id, &state.jobs.submit(&client_id)),
+            false => client.clone(),
+        };
+        if state.request_queue.is_empty() && state.worker_pool.has_available() {
+            dispatch_request(
+                &backend_socket,
+                state,
+                &reply_to,
+                &request,
+                header.tenant(),
+                1,
+            );
+        } else if state
+            .request_queue
+            .push(&reply_to, &request, header.tenant(), header.priority)
+        {
+            println!(
+                "Request of client #{} is queued with {:?} priority ({} waiting)",
+                reply_to,
+                header.priority,
+                state.request_queue.len()
+            );
+        } else {
+            state.tenant_quotas.release(header.tenant());
+            if let Some(job_id) = &reply_to.job_id {
+                state.jobs.forget(job_id);
+            }
+            self.reply_rejected(
+                &frontend_socket,
+                &mut state.jobs,
+                &client,
+                REP_503_SERVICE_UNAVAILABLE,
+                format!(
+                    "Too many requests waiting for a worker ({})",
+                    self.config.queue_max_depth
+                )
+                .as_str(),
+            );
+            return Ok(());
+        }
+
+        // a job is acknowledged right away, its client does not wait for the PDF
+        if let Some(job_id) = &reply_to.job_id {
+            self.reply_job(
+                &frontend_socket,
+                &client_id,
+                REP_202_ACCEPTED,
+                "Job accepted",
+                state.jobs.status(job_id, &client_id),
+            );
+        }
+        Ok(())
+    }
+
+    fn cancel_request(
+        &self,
+        backend_socket: &zmq::Socket,
+        frontend_socket: &zmq::Socket,
+        state: &mut BrokerState,
+        reply_to: &ReplyTo,
+    ) {
+        match self.take_request(&backend_socket, state, &reply_to) {
+            Cancellation::Done => self.reply_rejected(
+                &frontend_socket,
+                &mut state.jobs,
+                &reply_to,
+                REP_499_CANCELLED,
+                "Request was cancelled",
+            ),
+            Cancellation::Refused => self.reply_rejected(
+                &frontend_socket,
+                &mut state.jobs,
+                &reply_to,
+                REP_409_CONFLICT,
+                MSG_CANNOT_ABORT_RENDER,
+            ),
+            Cancellation::Unknown => self.reply_rejected(
+                &frontend_socket,
+                &mut state.jobs,
+                &reply_to,
+                REP_404_NOT_FOUND,
+                "No such request, or it was replied to already",
+            ),
+        }
+    }
 
-        let client_id = recv_string(
+    fn cancel_job(
+        &self,
+        backend_socket: &zmq::Socket,
+        frontend_socket: &zmq::Socket,
+        state: &mut BrokerState,
+        client_id: &Identity,
+        job_id: &str,
+    ) {
+        // the job then fails with the very reply its client gets for cancelling it
+        if let Some(reply_to) = state.jobs.reply_to(job_id, client_id) {
+            let cancelled = self.take_request(&backend_socket, state, &reply_to);
+            if cancelled == Cancellation::Refused {
+                self.reply_job(
+                    &frontend_socket,
+                    &client_id,
+                    REP_409_CONFLICT,
+                    MSG_CANNOT_ABORT_RENDER,
+                    state.jobs.status(job_id, client_id),
+                );
+                return;
+            }
+            if cancelled == Cancellation::Done {
+                self.reply_rejected(
+                    &frontend_socket,
+                    &mut state.jobs,
+                    &reply_to,
+                    REP_499_CANCELLED,
+                    "Job was cancelled",
+                );
+                self.reply_job(
+                    &frontend_socket,
+                    &client_id,
+                    REP_499_CANCELLED,
+                    "Job was cancelled",
+                    state.jobs.status(job_id, client_id),
+                );
+                return;
+            }
+        }
+        self.reply_job(
             &frontend_socket,
-            "failed reading <ID> from client's envelope",
+            &client_id,
+            REP_404_NOT_FOUND,
+            format!("No job {} to cancel, or it finished already", job_id).as_str(),
+            state.jobs.status(job_id, client_id),
         );
-        assert_empty(
+    }
+
+    // Takes a request away from wherever it is, releasing its tenant's quota,
+    // unless it cannot be.
+    fn take_request(
+        &self,
+        backend_socket: &zmq::Socket,
+        state: &mut BrokerState,
+        reply_to: &ReplyTo,
+    ) -> Cancellation {
+        // a request no worker has yet is just forgotten, while a render cannot be
+        // interrupted, so a worker the broker spawned exits to be replaced (or
+        // tells it is ready again, if it was done already); any other worker
+        // is not in the broker's hands to replace, so the render is left alone
+        // and its reply delivered as usual
+        if let Some(worker_id) = state.worker_pool.busy_with(reply_to) {
+            if !self.is_spawned(state, worker_id) {
+                println!(
+                    "Worker #{} is remote and keeps rendering for client #{}",
+                    worker_id, reply_to
+                );
+                return Cancellation::Refused;
+            }
+        }
+        let retry_position = state
+            .retry_queue
+            .iter()
+            .position(|in_flight| in_flight.reply_to == *reply_to);
+        let tenant = if let Some(pending) = state.request_queue.remove(reply_to) {
+            println!("Queued request of client #{} is cancelled", reply_to);
+            Some(pending.tenant)
+        } else if let Some(position) = retry_position {
+            println!("Request of client #{} to retry is cancelled", reply_to);
+            state
+                .retry_queue
+                .remove(position)
+                .map(|in_flight| in_flight.tenant)
+        } else if let Some((worker_id, in_flight)) = state.worker_pool.abort(reply_to) {
+            println!(
+                "Worker #{} will abort rendering of client #{}",
+                worker_id, reply_to
+            );
+            state.worker_pool.drain(&worker_id);
+            send_worker_command(&backend_socket, &worker_id, MSG_CANCEL);
+            Some(in_flight.tenant)
+        } else {
+            None
+        };
+
+        match tenant {
+            Some(tenant) => {
+                state.tenant_quotas.release(&tenant);
+                Cancellation::Done
+            }
+            None => Cancellation::Unknown,
+        }
+    }
+
+    fn reply_job_status(
+        &self,
+        frontend_socket: &zmq::Socket,
+        state: &BrokerState,
+        client_id: &Identity,
+        job_id: &str,
+    ) {
+        match state.jobs.status(job_id, client_id) {
+            Some(status) => self.reply_job(
+                &frontend_socket,
+                &client_id,
+                REP_200_SUCCESS,
+                format!("Job is {}", status.state).as_str(),
+                Some(status),
+            ),
+            None => self.reply_job(
+                &frontend_socket,
+                &client_id,
+                REP_404_NOT_FOUND,
+                format!("No job {}, or it expired", job_id).as_str(),
+                None,
+            ),
+        }
+    }
+
+    fn reply_job_result(
+        &self,
+        frontend_socket: &zmq::Socket,
+        state: &BrokerState,
+        client_id: &Identity,
+        job_id: &str,
+    ) {
+        // a finished job gets the very reply its client would have got waiting,
+        // until it expires
+        match state.jobs.result(job_id, client_id) {
+            Some(result) => {
+                println!(
+                    "Reply {} of job {} to client #{}",
+                    result.code, job_id, client_id
+                );
+                send_reply(
+                    &frontend_socket,
+                    &ReplyTo::client(client_id),
+                    &result.worker_id,
+                    &result.code,
+                    zmq::Message::from(&result.content[..]),
+                    result
+                        .attachments
+                        .iter()
+                        .map(|attachment| zmq::Message::from(&attachment[..]))
+                        .collect(),
+                );
+            }
+            None => match state.jobs.status(job_id, client_id) {
+                Some(status) => self.reply_job(
+                    &frontend_socket,
+                    &client_id,
+                    REP_202_ACCEPTED,
+                    format!("Job is {}, not finished yet", status.state).as_str(),
+                    Some(status),
+                ),
+                None => self.reply_job_status(&frontend_socket, state, &client_id, job_id),
+            },
+        }
+    }
+
+    fn reply_job(
+        &self,
+        frontend_socket: &zmq::Socket,
+        client_id: &Identity,
+        code: &str,
+        message: &str,
+        status: Option<JobStatus>,
+    ) {
+        println!("Reply {} to client #{}: {}", code, client_id, message);
+        let reply = match status {
+            Some(status) => RenderReply::job(code, message, status),
+            None => RenderReply::error(code, message),
+        };
+        send_reply(
             &frontend_socket,
-            "failed reading 1nd <EMPTY> of client's envelope",
+            &ReplyTo::client(client_id),
+            format!("B{}", self.id).as_str(),
+            code,
+            zmq::Message::from(reply.to_json().into_bytes()),
+            Vec::new(),
         );
-        let request = recv_string(
+    }
+
+    fn discard_client_envelope(
+        &self,
+        frontend_socket: &zmq::Socket,
+        state: &mut BrokerState,
+        malformed: Malformed,
+    ) {
+        state.malformed_envelopes += 1;
+        println!(
+            "Discarded malformed envelope from client #{}: {} ({} so far)",
+            describe_sender(&malformed),
+            malformed.reason,
+            state.malformed_envelopes
+        );
+        if let (true, Some(client_id)) = (malformed.can_reply, &malformed.sender) {
+            self.reply_rejected(
+                &frontend_socket,
+                &mut state.jobs,
+                &ReplyTo::request(client_id, malformed.request_id.as_deref()),
+                reply_code(ErrorKind::Protocol),
+                malformed.reason.as_str(),
+            );
+        }
+    }
+
+    fn discard_worker_envelope(
+        &self,
+        backend_socket: &zmq::Socket,
+        frontend_socket: &zmq::Socket,
+        state: &mut BrokerState,
+        malformed: Malformed,
+    ) {
+        state.malformed_envelopes += 1;
+        println!(
+            "Discarded malformed envelope from worker #{}: {} ({} so far)",
+            describe_sender(&malformed),
+            malformed.reason,
+            state.malformed_envelopes
+        );
+
+        // a broken reply is as good as lost, but the worker that sent it is
+        // still alive and done with the request
+        if !malformed.can_reply {
+            return;
+        }
+        let worker_id = match &malformed.sender {
+            Some(sender) => sender.to_string(),
+            None => return,
+        };
+        if let Some(in_flight) = state.worker_pool.complete(&worker_id) {
+            state.tenant_quotas.release(&in_flight.tenant);
+            send_broker_reply(
+                &frontend_socket,
+                &mut state.jobs,
+                &in_flight.reply_to,
+                &worker_id,
+                REP_502_BAD_GATEWAY,
+                format!("Worker #{} sent a malformed reply", worker_id).as_str(),
+            );
+            if state.worker_pool.is_draining(&worker_id) {
+                self.retire_worker(&backend_socket, state, &worker_id);
+            } else {
+                state.worker_pool.release(&worker_id);
+            }
+        }
+    }
+
+    fn reply_rejected(
+        &self,
+        frontend_socket: &zmq::Socket,
+        jobs: &mut JobTable,
+        reply_to: &ReplyTo,
+        code: &str,
+        message: &str,
+    ) {
+        // no worker took part, so broker signs the reply itself
+        send_broker_reply(
             &frontend_socket,
-            "failed reading <REQUEST> from client's envelope",
+            jobs,
+            &reply_to,
+            format!("B{}", self.id).as_str(),
+            code,
+            message,
         );
+    }
 
-        println!("Current available workers: {:?}", available_workers);
-        let worker_id = available_workers
-            .pop_back()
-            .expect("failed to get an available worker");
+    fn autoscale(&self, backend_socket: &zmq::Socket, state: &mut BrokerState) {
+        let now = Instant::now();
+        let policy = &self.config.scaling;
 
-        // multipart envelope from client to worker:
-        //   WORKER, EMPTY, CLIENT, EMPTY, REQUEST
-        let reply_envelope = vec![
-            worker_id.as_bytes().to_vec(),
-            "".as_bytes().to_vec(),
-            client_id.as_bytes().to_vec(),
-            "".as_bytes().to_vec(),
-            request.as_bytes().to_vec(),
-        ];
-
-        // forward request envelope to given worker
-        send_multipart(
-            &backend_socket,
-            reply_envelope,
-            format!(
-                "failed forwarding request from client #{} to worker #{}",
-                client_id, worker_id
-            )
-            .as_str(),
+        let spawned = self.spawned_workers(state);
+        let load = Load {
+            spawned: spawned.len(),
+            starting: spawned
+                .values()
+                .filter(|identity| identity.is_none())
+                .count(),
+            queued: state.request_queue.len(),
+            longest_wait: state.request_queue.longest_wait(now),
+        };
+
+        let to_start = policy.workers_to_start(&load);
+        if to_start > 0 {
+            println!("Will start {} more workers for {:?}", to_start, load);
+        }
+        for _ in 0..to_start {
+            match Self::start_worker(&self.config) {
+                Ok(child) => Self::register_worker(self.running_workers.clone(), child),
+                Err(reason) => println!("Failed to start another worker: {}", reason),
+            }
+        }
+
+        // only spawned workers are retired, remote ones are up to whoever started them
+        let idle: Vec<String> = state
+            .worker_pool
+            .idle_workers(now, policy.scale_down_after)
+            .into_iter()
+            .filter(|worker_id| {
+                spawned
+                    .values()
+                    .any(|identity| identity.as_deref() == Some(worker_id.as_str()))
+            })
+            .collect();
+        let to_retire = policy.workers_to_retire(&load, idle.len());
+        for worker_id in idle.into_iter().take(to_retire) {
+            println!("Will retire worker #{} which is idle", worker_id);
+            self.retire_worker(backend_socket, state, &worker_id);
+        }
+    }
+
+    // Spawned workers still meant to run, along with their identity once ready.
+    fn spawned_workers(&self, state: &BrokerState) -> HashMap<u32, Option<String>> {
+        let hostname = get_hostname();
+        let mut spawned: HashMap<u32, Option<String>> = self
+            .running_workers
+            .read()
+            .expect("failed to acquire read lock of running workers")
+            .values()
+            .filter(|worker| !worker.retiring)
+            .map(|worker| (worker.pid, None))
+            .collect();
+        for worker_id in state.worker_pool.worker_ids() {
+            if let Some(worker) = state.worker_pool.get(&worker_id) {
+                if worker.capabilities.host == hostname {
+                    if let Some(identity) = spawned.get_mut(&worker.capabilities.pid) {
+                        *identity = Some(worker_id);
+                    }
+                }
+            }
+        }
+        spawned
+    }
+
+    fn is_spawned(&self, state: &BrokerState, worker_id: &str) -> bool {
+        self.spawned_workers(state)
+            .values()
+            .any(|identity| identity.as_deref() == Some(worker_id))
+    }
+
+    fn restart_workers(&self, backend_socket: &zmq::Socket, state: &mut BrokerState) {
+        if state.restarting.is_empty() {
+            return;
+        }
+
+        // one at a time, so the cluster keeps its capacity while restarting
+        let spawned = self.spawned_workers(state);
+        if spawned.values().any(|identity| identity.is_none()) {
+            return;
+        }
+        state.restarting.retain(|pid| spawned.contains_key(pid));
+        let pid = match state.restarting.iter().next() {
+            Some(pid) => *pid,
+            None => {
+                println!("All workers are restarted");
+                return;
+            }
+        };
+        state.restarting.remove(&pid);
+
+        let worker_id = spawned[&pid].clone().unwrap();
+        println!("Will restart worker #{}", worker_id);
+        match Self::start_worker(&self.config) {
+            Ok(child) => Self::register_worker(self.running_workers.clone(), child),
+            Err(reason) => println!("Failed to start a replacement worker: {}", reason),
+        }
+        // a busy worker gets stopped once it replies
+        if state.worker_pool.drain(&worker_id) {
+            self.retire_worker(backend_socket, state, &worker_id);
+        }
+    }
+
+    fn recycle_workers(&self, backend_socket: &zmq::Socket, state: &mut BrokerState) {
+        let max_renders = self.config.max_requests_per_worker;
+        let max_rss_kb = self.config.max_worker_rss_kb;
+        if max_renders.is_none() && max_rss_kb.is_none() {
+            return;
+        }
+
+        // memory is only known of spawned workers, which is fine since only those
+        // can be replaced anyway
+        let hostname = get_hostname();
+        let rss_kb: HashMap<u32, u64> = self
+            .running_workers
+            .read()
+            .expect("failed to acquire read lock of running workers")
+            .values()
+            .filter(|worker| !worker.retiring)
+            .map(|worker| (worker.pid, worker.rss_kb))
+            .collect();
+
+        for worker_id in state.worker_pool.worker_ids() {
+            let (pid, renders) = match state.worker_pool.get(&worker_id) {
+                Some(worker) if worker.capabilities.host == hostname && !worker.draining => {
+                    (worker.capabilities.pid, worker.renders)
+                }
+                _ => continue,
+            };
+            let worker_rss_kb = match rss_kb.get(&pid) {
+                Some(worker_rss_kb) => *worker_rss_kb,
+                None => continue,
+            };
+
+            let worn_out = max_renders.map_or(false, |max| renders >= max)
+                || max_rss_kb.map_or(false, |max| worker_rss_kb > max);
+            if !worn_out {
+                continue;
+            }
+
+            println!(
+                "Worker #{} will be recycled after {} renders using {}kB",
+                worker_id, renders, worker_rss_kb
+            );
+            match Self::start_worker(&self.config) {
+                Ok(child) => Self::register_worker(self.running_workers.clone(), child),
+                Err(reason) => println!("Failed to start a replacement worker: {}", reason),
+            }
+            // a busy worker gets stopped once it replies
+            if state.worker_pool.drain(&worker_id) {
+                self.retire_worker(backend_socket, state, &worker_id);
+            }
+        }
+    }
+
+    fn retire_worker(
+        &self,
+        backend_socket: &zmq::Socket,
+        state: &mut BrokerState,
+        worker_id: &str,
+    ) {
+        let hostname = get_hostname();
+        let pid = state
+            .worker_pool
+            .remove(&worker_id)
+            .filter(|worker| worker.capabilities.host == hostname)
+            .map(|worker| worker.capabilities.pid);
+
+        // a retiring worker exits on its own, rather than being replaced
+        let spawned = match pid {
+            Some(pid) => self
+                .running_workers
+                .write()
+                .expect("failed to acquire write lock of running workers")
+                .get_mut(&pid)
+                .map(|worker| worker.retiring = true)
+                .is_some(),
+            None => false,
+        };
+
+        if spawned {
+            // it answers GONE, which is fine for a worker the pool forgot already
+            state.stopping.insert(worker_id.to_string(), Instant::now());
+            send_worker_command(&backend_socket, &worker_id, MSG_STOP_WORKER);
+        } else {
+            // remote workers are up to whoever started them, so they are just let go
+            // and reconnect once they miss the broker's heartbeats
+            println!("Worker #{} is remote and is let go", worker_id);
+        }
+    }
+
+    fn start_draining(
+        &self,
+        backend_socket: &zmq::Socket,
+        frontend_socket: &zmq::Socket,
+        state: &mut BrokerState,
+    ) {
+        println!(
+            "Will drain workers for {}s at most",
+            self.config.drain_timeout.as_secs()
         );
-        Ok(())
+        state.draining_since = Some(Instant::now());
+        state.restarting.clear();
+
+        // requests not handed to a worker yet will not be
+        let mut waiting: Vec<(ReplyTo, String)> = state
+            .retry_queue
+            .drain(..)
+            .map(|in_flight| (in_flight.reply_to, in_flight.tenant))
+            .collect();
+        while let Some(pending) = state.request_queue.pop() {
+            waiting.push((pending.reply_to, pending.tenant));
+        }
+        for (reply_to, tenant) in waiting {
+            state.tenant_quotas.release(&tenant);
+            self.reply_rejected(
+                &frontend_socket,
+                &mut state.jobs,
+                &reply_to,
+                REP_503_SERVICE_UNAVAILABLE,
+                "Broker is shutting down",
+            );
+        }
+
+        // idle workers go right away, busy ones once they reply
+        for worker_id in state.worker_pool.worker_ids() {
+            if state.worker_pool.drain(&worker_id) {
+                self.retire_worker(&backend_socket, state, &worker_id);
+            }
+        }
+    }
+
+    fn abort_draining(&self, frontend_socket: &zmq::Socket, state: &mut BrokerState) {
+        println!("Workers did not drain in time and will be killed");
+        for worker_id in state.worker_pool.worker_ids() {
+            if let Some(in_flight) = state
+                .worker_pool
+                .remove(&worker_id)
+                .and_then(|worker| worker.in_flight)
+            {
+                self.reply_rejected(
+                    &frontend_socket,
+                    &mut state.jobs,
+                    &in_flight.reply_to,
+                    REP_503_SERVICE_UNAVAILABLE,
+                    "Broker shut down before rendering finished",
+                );
+            }
+        }
+    }
+
+    fn can_retry(&self, in_flight: &InFlight) -> bool {
+        in_flight.attempts <= self.config.max_retries
+    }
+
+    fn retry(&self, state: &mut BrokerState, in_flight: InFlight) {
+        println!(
+            "Request of client #{} will be retried (attempt {} of {})",
+            in_flight.reply_to,
+            in_flight.attempts + 1,
+            self.config.max_retries + 1
+        );
+        if let Some(job_id) = &in_flight.reply_to.job_id {
+            state.jobs.set_state(job_id, JobState::Queued);
+        }
+        state.retry_queue.push_back(in_flight);
+    }
+
+    fn retry_or_fail(
+        &self,
+        frontend_socket: &zmq::Socket,
+        state: &mut BrokerState,
+        worker_id: &str,
+        in_flight: InFlight,
+    ) {
+        // no worker takes requests while draining, so there is nowhere to retry
+        if state.draining_since.is_none() && self.can_retry(&in_flight) {
+            self.retry(state, in_flight);
+        } else {
+            state.tenant_quotas.release(&in_flight.tenant);
+            send_broker_reply(
+                &frontend_socket,
+                &mut state.jobs,
+                &in_flight.reply_to,
+                &worker_id,
+                REP_502_BAD_GATEWAY,
+                format!(
+                    "Worker #{} was lost while rendering, gave up after {} attempts",
+                    worker_id, in_flight.attempts
+                )
+                .as_str(),
+            );
+        }
     }
 
     fn watch_workers(&self) -> Result<()> {
         let id = self.id;
         let stop_signal = self.stop_signal.clone();
-        let worker_binpath = self.worker_binpath.clone();
-        let worker_outpath = self.worker_outpath.clone();
-        let worker_timeout = self.worker_timeout;
         let running_workers = self.running_workers.clone();
 
         std::thread::spawn(move || {
@@ -342,45 +1342,34 @@ impl Broker {
                 thread::sleep(Duration::from_secs(5)); // TODO: parametize it
 
                 println!("--> [watch_workers]");
-                // actual workers running now under this broker
-                let current_running_workers =
-                    Self::get_current_running_workers(&id, |parent_id, pid, process| {
-                        println!(
-                            "[{}:{}] {} {}kB {}%",
-                            parent_id,
-                            pid,
-                            process.name(),
-                            process.memory(),
-                            process.cpu_usage()
-                        );
-                    });
+                // usage of workers running now under this broker
+                Self::get_current_running_workers(&id, |parent_id, pid, process| {
+                    println!(
+                        "[{}:{}] {} {}kB {}%",
+                        parent_id,
+                        pid,
+                        process.name(),
+                        process.memory(),
+                        process.cpu_usage()
+                    );
+                    // kept for the event loop to recycle workers using too much memory
+                    if let Some(worker) = running_workers
+                        .write()
+                        .expect("failed to acquire write lock of running workers")
+                        .get_mut(&pid)
+                    {
+                        worker.rss_kb = process.memory();
+                    }
+                });
 
-                // house keeping
+                // house keeping of spawned workers only, since remote ones are up to
+                // whoever started them and are dropped from the pool once their
+                // heartbeats stop; autoscaling fills in for dead ones
                 if !stop_signal.load(Ordering::SeqCst) {
-                    let pids: Vec<u32> = running_workers
-                        .read()
-                        .expect("failed to acquire lock of running workers")
-                        .iter()
-                        .map(|kv| *kv.0)
-                        .collect();
-                    for pid in pids {
-                        if !current_running_workers.contains(&pid) {
-                            println!("Will remove worker #{} which is dead", pid);
-                            Self::remove_worker(running_workers.clone(), &pid);
-
-                            // another check before start new workers, because it might be
-                            // close here when stop signal was triggered
-                            if !stop_signal.load(Ordering::SeqCst) {
-                                println!("Will start another worker to fill in");
-                                let child = Self::start_worker(
-                                    &worker_binpath,
-                                    &worker_outpath,
-                                    &worker_timeout,
-                                )
-                                .expect("failed to start worker");
-                                Self::register_worker(running_workers.clone(), child);
-                            }
-                        }
+                    let dead_pids = Self::get_dead_workers(running_workers.clone());
+                    for pid in dead_pids {
+                        println!("Will remove worker #{} which is dead", pid);
+                        Self::remove_worker(running_workers.clone(), &pid);
                     }
                 }
                 println!("<-- [watch_workers]");
@@ -389,6 +1378,18 @@ impl Broker {
         Ok(())
     }
 
+    fn get_dead_workers(running_workers: Arc<RwLock<HashMap<u32, WorkerRef>>>) -> Vec<u32> {
+        running_workers
+            .write()
+            .expect("failed to acquire write lock of running workers")
+            .values_mut()
+            .filter_map(|worker| match worker.os_process.try_wait() {
+                Ok(None) => None,
+                _ => Some(worker.pid),
+            })
+            .collect()
+    }
+
     fn get_current_running_workers<F: Fn(u32, u32, &Process)>(id: &u32, callback: F) -> Vec<u32> {
         let mut worker_processes: Vec<u32> = Vec::new();
         let sys = System::new_all();
@@ -410,11 +1411,32 @@ impl Broker {
     }
 
     fn terminate_workers(&self) {
-        println!("Will give workers time to gracefully shutdown");
-        thread::sleep(Duration::from_secs(5)); // TODO: parametize it
+        // workers that answered GONE are on their way out, so give them a moment
+        println!("Will give workers time to exit");
+        let deadline = Instant::now() + self.config.heartbeat_interval;
+        while Instant::now() < deadline {
+            for pid in Self::get_dead_workers(self.running_workers.clone()) {
+                Self::remove_worker(self.running_workers.clone(), &pid);
+            }
+            let running = self
+                .running_workers
+                .read()
+                .expect("failed to acquire read lock of running workers")
+                .len();
+            if running == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
 
         println!("Will ensure all workers terminate one way or another");
-        Self::get_current_running_workers(&self.id, |_, pid, process| {
+        Self::kill_workers(self.id);
+    }
+
+    // Kills whatever workers the broker of the given ID spawned, which is all
+    // that is left to do when it cannot wait for them to drain.
+    pub fn kill_workers(id: u32) {
+        Self::get_current_running_workers(&id, |_, pid, process| {
             println!("Worker #{} still running and will be terminated", pid);
             let killed = process.kill(Signal::Kill);
             if killed {
@@ -424,6 +1446,170 @@ impl Broker {
     }
 }
 
+fn send_worker_command(backend_socket: &zmq::Socket, worker_id: &str, command: &str) {
+    // multipart envelope from broker to worker:
+    //   WORKER, EMPTY, COMMAND
+    send_multipart(
+        &backend_socket,
+        vec![
+            worker_id.as_bytes().to_vec(),
+            b"".to_vec(),
+            command.as_bytes().to_vec(),
+        ],
+        format!("failed sending <{}> to worker #{}", command, worker_id).as_str(),
+    );
+}
+
+fn describe_sender(malformed: &Malformed) -> String {
+    match &malformed.sender {
+        Some(sender) => sender.to_string(),
+        None => String::from("?"),
+    }
+}
+
+fn dispatch_request(
+    backend_socket: &zmq::Socket,
+    state: &mut BrokerState,
+    reply_to: &ReplyTo,
+    request: &[u8],
+    tenant: &str,
+    attempts: u32,
+) {
+    println!(
+        "Current available workers: {:?}",
+        state.worker_pool.available()
+    );
+    let worker_id = state
+        .worker_pool
+        .next_available()
+        .expect("failed to get an available worker");
+    state
+        .worker_pool
+        .assign(&worker_id, &reply_to, request, tenant, attempts);
+    if let Some(job_id) = &reply_to.job_id {
+        state.jobs.set_state(job_id, JobState::Rendering);
+    }
+
+    // multipart envelope from client to worker:
+    //   WORKER, EMPTY, CLIENT, EMPTY, REQUEST
+    let reply_envelope = vec![
+        zmq::Message::from(worker_id.as_str()),
+        zmq::Message::new(),
+        zmq::Message::from(reply_to.client_id.clone()),
+        zmq::Message::new(),
+        zmq::Message::from(request),
+    ];
+
+    // forward request envelope to given worker
+    send_multipart(
+        &backend_socket,
+        reply_envelope,
+        format!(
+            "failed forwarding request from client #{} to worker #{}",
+            reply_to, worker_id
+        )
+        .as_str(),
+    );
+}
+
+// Sends a reply to the client waiting for it or, for a job, keeps it for its
+// client to fetch later.
+fn deliver_reply(
+    frontend_socket: &zmq::Socket,
+    jobs: &mut JobTable,
+    reply_to: &ReplyTo,
+    worker_id: &str,
+    code: &str,
+    content: zmq::Message,
+    attachments: Vec<zmq::Message>,
+) {
+    match &reply_to.job_id {
+        Some(job_id) => {
+            println!(
+                "Job {} of client #{} finished with {}",
+                job_id, reply_to.client_id, code
+            );
+            let result = JobResult {
+                worker_id: worker_id.to_string(),
+                code: code.to_string(),
+                content: content.to_vec(),
+                attachments: attachments.iter().map(|frame| frame.to_vec()).collect(),
+            };
+            jobs.finish(job_id, result, Instant::now());
+        }
+        None => send_reply(
+            &frontend_socket,
+            &reply_to,
+            worker_id,
+            code,
+            content,
+            attachments,
+        ),
+    }
+}
+
+fn send_reply(
+    frontend_socket: &zmq::Socket,
+    reply_to: &ReplyTo,
+    worker_id: &str,
+    code: &str,
+    content: zmq::Message,
+    attachments: Vec<zmq::Message>,
+) {
+    // multipart envelope from worker to client, with the ID the client gave
+    // its request, if any:
+    //   CLIENT, EMPTY, WORKER, EMPTY, REPLY, EMPTY, CONTENT
+    //   CLIENT, EMPTY, WORKER, EMPTY, REPLY, EMPTY, CONTENT, PDF
+    //   CLIENT, EMPTY, REQUEST-ID, WORKER, EMPTY, REPLY, EMPTY, CONTENT[, PDF]
+    let mut reply_envelope = vec![
+        zmq::Message::from(reply_to.client_id.clone()),
+        zmq::Message::new(),
+    ];
+    if let Some(request_id) = &reply_to.request_id {
+        reply_envelope.push(zmq::Message::from(request_id.as_str()));
+    }
+    reply_envelope.extend(vec![
+        zmq::Message::from(worker_id),
+        zmq::Message::new(),
+        zmq::Message::from(code),
+        zmq::Message::new(),
+        content,
+    ]);
+    reply_envelope.extend(attachments);
+    send_multipart(
+        &frontend_socket,
+        reply_envelope,
+        format!(
+            "failed sending reply from worker #{} to client #{}",
+            worker_id, reply_to
+        )
+        .as_str(),
+    );
+}
+
+fn send_broker_reply(
+    frontend_socket: &zmq::Socket,
+    jobs: &mut JobTable,
+    reply_to: &ReplyTo,
+    worker_id: &str,
+    code: &str,
+    message: &str,
+) {
+    println!("Reply {} to client #{}: {}", code, reply_to, message);
+
+    // same envelope as if it came from the worker
+    let reply = RenderReply::error(code, message);
+    deliver_reply(
+        &frontend_socket,
+        jobs,
+        &reply_to,
+        worker_id,
+        code,
+        zmq::Message::from(reply.to_json().into_bytes()),
+        Vec::new(),
+    );
+}
+
 // Unit testing
 //
 
@@ -433,26 +1619,370 @@ mod tests {
 
     #[test]
     fn create_broker() {
-        let broker = Broker::new(
-            0,
-            Arc::new(AtomicBool::new(false)),
+        let mut config = BrokerConfig::new(
             2,
             Path::new("bin"),
             Path::new("out"),
             Duration::from_secs(5),
         );
-        assert_eq!(broker.worker_instances, 2);
-        assert_eq!(broker.worker_binpath.as_os_str(), "bin");
-        assert_eq!(broker.worker_outpath.as_os_str(), "out");
+        config.frontend_endpoint = String::from("ipc:///tmp/wk-frontend");
+        let broker = Broker::new(0, Arc::new(AtomicBool::new(false)), config);
+        assert_eq!(broker.config.worker_instances, 2);
+        assert_eq!(broker.config.worker_binpath.as_os_str(), "bin");
+        assert_eq!(broker.config.worker_outpath.as_os_str(), "out");
+        assert_eq!(broker.config.frontend_endpoint, "ipc:///tmp/wk-frontend");
+        assert_eq!(broker.config.backend_endpoint, DEFAULT_BACKEND_ENDPOINT);
+    }
+
+    fn bind_broker(name: &str) -> (Broker, BrokerState, zmq::Context, zmq::Socket, zmq::Socket) {
+        let mut config = BrokerConfig::new(
+            1,
+            Path::new("bin"),
+            Path::new("out"),
+            Duration::from_secs(5),
+        );
+        config.frontend_endpoint = format!("inproc://{}-frontend", name);
+        config.backend_endpoint = format!("inproc://{}-backend", name);
+        let broker = Broker::new(0, Arc::new(AtomicBool::new(false)), config);
+        let state = BrokerState::new(&broker.config);
+
+        let context = zmq::Context::new();
+        let frontend_socket = context.socket(zmq::ROUTER).unwrap();
+        frontend_socket
+            .bind(&broker.config.frontend_endpoint)
+            .unwrap();
+        let backend_socket = context.socket(zmq::ROUTER).unwrap();
+        backend_socket
+            .bind(&broker.config.backend_endpoint)
+            .unwrap();
+        (broker, state, context, frontend_socket, backend_socket)
+    }
+
+    // Clients and workers alike talk to the broker through a DEALER socket here,
+    // so they only wait that long for what they expect.
+    fn connect_peer(context: &zmq::Context, endpoint: &str, identity: &str) -> zmq::Socket {
+        let socket = context.socket(zmq::DEALER).unwrap();
+        socket.set_identity(identity.as_bytes()).unwrap();
+        socket.set_rcvtimeo(1000).unwrap();
+        socket.connect(endpoint).unwrap();
+        socket
+    }
+
+    fn send_frames(socket: &zmq::Socket, frames: &[&[u8]]) {
+        socket.send_multipart(frames.iter(), 0).unwrap();
+    }
+
+    fn recv_frames(socket: &zmq::Socket) -> Vec<Vec<u8>> {
+        socket.recv_multipart(0).unwrap()
+    }
+
+    fn send_ready(worker_socket: &zmq::Socket) {
+        send_ready_as(worker_socket, WorkerCapabilities::new("localhost", 42));
+    }
+
+    fn send_ready_as(worker_socket: &zmq::Socket, capabilities: WorkerCapabilities) {
+        let capabilities = capabilities.to_json();
+        send_frames(
+            worker_socket,
+            &[b"", MSG_WORKER_IS_READY.as_bytes(), capabilities.as_bytes()],
+        );
+    }
+
+    // Sends a request with an ID to the only worker around, which gets it.
+    fn render_on_worker(
+        broker: &Broker,
+        state: &mut BrokerState,
+        sockets: (&zmq::Socket, &zmq::Socket),
+        client_socket: &zmq::Socket,
+        worker_socket: &zmq::Socket,
+    ) {
+        let (frontend_socket, backend_socket) = sockets;
+        send_frames(client_socket, &[b"", CMD_RENDER.as_bytes(), b"R1", b"{}"]);
+        broker
+            .handle_frontend_talking(backend_socket, frontend_socket, state)
+            .unwrap();
+        assert_eq!(recv_frames(worker_socket)[1], b"C1");
+    }
+
+    fn cancel_render(
+        broker: &Broker,
+        state: &mut BrokerState,
+        sockets: (&zmq::Socket, &zmq::Socket),
+        client_socket: &zmq::Socket,
+    ) {
+        let (frontend_socket, backend_socket) = sockets;
+        send_frames(client_socket, &[b"", MSG_CANCEL.as_bytes(), b"R1"]);
+        broker
+            .handle_frontend_talking(backend_socket, frontend_socket, state)
+            .unwrap();
+        let reply = recv_frames(client_socket);
+        assert_eq!(reply[1], b"R1");
+        assert_eq!(reply[4], REP_499_CANCELLED.as_bytes());
+    }
+
+    #[test]
+    fn discard_malformed_envelopes() {
+        let (broker, mut state, context, frontend_socket, backend_socket) =
+            bind_broker("malformed");
+        let client_socket = connect_peer(&context, &broker.config.frontend_endpoint, "C1");
+        let worker_socket = connect_peer(&context, &broker.config.backend_endpoint, "W1");
+
+        // client that can be told gets a 400 reply
+        send_frames(&client_socket, &[b"", b"FOO", b"{}"]);
+        broker
+            .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        let reply = recv_frames(&client_socket);
+        assert_eq!(reply[1], b"B0");
+        assert_eq!(reply[3], REP_400_BAD_REQUEST.as_bytes());
+        assert_eq!(state.malformed_envelopes, 1);
+
+        // worker telling gibberish about itself is not taken in
+        send_frames(
+            &worker_socket,
+            &[b"", MSG_WORKER_IS_READY.as_bytes(), b"garbage"],
+        );
+        broker
+            .handle_backend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        assert!(state.worker_pool.is_empty());
+        assert_eq!(state.malformed_envelopes, 2);
+
+        // broker keeps serving all the same
+        send_ready(&worker_socket);
+        broker
+            .handle_backend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        assert_eq!(state.worker_pool.len(), 1);
+        assert_eq!(state.malformed_envelopes, 2);
+    }
+
+    #[test]
+    fn forward_request_and_reply() {
+        let (broker, mut state, context, frontend_socket, backend_socket) = bind_broker("forward");
+        let client_socket = connect_peer(&context, &broker.config.frontend_endpoint, "C1");
+        let worker_socket = connect_peer(&context, &broker.config.backend_endpoint, "W1");
+
+        send_ready(&worker_socket);
+        broker
+            .handle_backend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+
+        // request goes to the only worker around
+        send_frames(&client_socket, &[b"", b"{}"]);
+        broker
+            .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        let request = recv_frames(&worker_socket);
+        assert_eq!(
+            request,
+            vec![b"".to_vec(), b"C1".to_vec(), b"".to_vec(), b"{}".to_vec()]
+        );
+        assert!(!state.worker_pool.has_available());
+
+        // and its reply back to the client, with the worker available again
+        send_frames(
+            &worker_socket,
+            &[b"", b"C1", b"", b"200", b"", b"{}", b"%PDF"],
+        );
+        broker
+            .handle_backend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        let reply = recv_frames(&client_socket);
+        assert_eq!(reply[1], b"W1");
+        assert_eq!(reply[3], REP_200_SUCCESS.as_bytes());
+        assert_eq!(reply[6], b"%PDF");
+        assert!(state.worker_pool.has_available());
+    }
+
+    #[test]
+    fn cancel_render_of_remote_worker() {
+        let (broker, mut state, context, frontend_socket, backend_socket) =
+            bind_broker("cancel-remote");
+        let client_socket = connect_peer(&context, &broker.config.frontend_endpoint, "C1");
+        let worker_socket = connect_peer(&context, &broker.config.backend_endpoint, "W1");
+        let sockets = (&frontend_socket, &backend_socket);
+
+        send_ready(&worker_socket);
+        broker
+            .handle_backend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        render_on_worker(&broker, &mut state, sockets, &client_socket, &worker_socket);
+        send_frames(&client_socket, &[b"", MSG_CANCEL.as_bytes(), b"R1"]);
+        broker
+            .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+
+        // a worker the broker cannot replace is left to finish, and the client
+        // is told so
+        let reply = recv_frames(&client_socket);
+        assert_eq!(reply[1], b"R1");
+        assert_eq!(reply[4], REP_409_CONFLICT.as_bytes());
+        assert!(worker_socket.recv_multipart(zmq::DONTWAIT).is_err());
+        assert!(!state.worker_pool.is_draining("W1"));
+
+        // and its reply is delivered as usual
+        send_frames(&worker_socket, &[b"", b"C1", b"", b"200", b"", b"{}"]);
+        broker
+            .handle_backend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        assert_eq!(recv_frames(&client_socket)[4], REP_200_SUCCESS.as_bytes());
+        assert!(state.worker_pool.has_available());
+    }
+
+    #[test]
+    fn cancel_render_of_spawned_worker() {
+        let (broker, mut state, context, frontend_socket, backend_socket) =
+            bind_broker("cancel-spawned");
+        let client_socket = connect_peer(&context, &broker.config.frontend_endpoint, "C1");
+        let worker_socket = connect_peer(&context, &broker.config.backend_endpoint, "W1");
+        let sockets = (&frontend_socket, &backend_socket);
+
+        // any process stands for a spawned worker, as long as broker knows it
+        let child = Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id();
+        Broker::register_worker(broker.running_workers.clone(), child);
+
+        send_ready_as(
+            &worker_socket,
+            WorkerCapabilities::new(&get_hostname(), pid),
+        );
+        broker
+            .handle_backend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        render_on_worker(&broker, &mut state, sockets, &client_socket, &worker_socket);
+        cancel_render(&broker, &mut state, sockets, &client_socket);
+
+        // it is told to abort, and not given anything else meanwhile
+        let command = recv_frames(&worker_socket);
+        assert_eq!(command[1], MSG_CANCEL.as_bytes());
+        assert!(state.worker_pool.is_draining("W1"));
+        assert!(!state.worker_pool.has_available());
+
+        let mut running_workers = broker.running_workers.write().unwrap();
+        running_workers
+            .get_mut(&pid)
+            .unwrap()
+            .os_process
+            .kill()
+            .unwrap();
     }
 
     #[test]
-    fn start_broker() {
-        assert!(true);
+    fn cancel_requests_of_their_own_client() {
+        let (broker, mut state, context, frontend_socket, backend_socket) =
+            bind_broker("cancel-own");
+        let sockets = (&frontend_socket, &backend_socket);
+        let first_socket = connect_peer(&context, &broker.config.frontend_endpoint, "C1");
+        let second_socket = connect_peer(&context, &broker.config.frontend_endpoint, "C2");
+
+        // no worker around, so both wait under the same request ID
+        for client_socket in &[&first_socket, &second_socket] {
+            send_frames(client_socket, &[b"", CMD_RENDER.as_bytes(), b"R1", b"{}"]);
+            broker
+                .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+                .unwrap();
+        }
+        assert_eq!(state.request_queue.len(), 2);
+
+        cancel_render(&broker, &mut state, sockets, &second_socket);
+        let first = ReplyTo::request(&Identity::from("C1"), Some("R1"));
+        assert_eq!(state.request_queue.len(), 1);
+        assert!(first_socket.recv_multipart(zmq::DONTWAIT).is_err());
+
+        // a request is only cancelled once
+        send_frames(&second_socket, &[b"", MSG_CANCEL.as_bytes(), b"R1"]);
+        broker
+            .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        assert_eq!(recv_frames(&second_socket)[4], REP_404_NOT_FOUND.as_bytes());
+        assert!(state.request_queue.remove(&first).is_some());
     }
 
     #[test]
-    fn stop_broker() {
-        assert!(true);
+    fn cancel_jobs_by_id() {
+        let (broker, mut state, context, frontend_socket, backend_socket) =
+            bind_broker("cancel-job");
+        let client_socket = connect_peer(&context, &broker.config.frontend_endpoint, "C1");
+        let other_socket = connect_peer(&context, &broker.config.frontend_endpoint, "C2");
+
+        send_frames(&client_socket, &[b"", CMD_SUBMIT.as_bytes(), b"{}"]);
+        broker
+            .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        let reply = recv_frames(&client_socket);
+        assert_eq!(reply[3], REP_202_ACCEPTED.as_bytes());
+        let job_id = RenderReply::from_json(&reply[5]).unwrap().job.unwrap().id;
+
+        // other clients know nothing of it, even given its ID
+        for command in &[CMD_CANCEL_JOB, CMD_STATUS, CMD_FETCH] {
+            send_frames(&other_socket, &[b"", command.as_bytes(), job_id.as_bytes()]);
+            broker
+                .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+                .unwrap();
+            assert_eq!(recv_frames(&other_socket)[3], REP_404_NOT_FOUND.as_bytes());
+        }
+        assert!(!state.request_queue.is_empty());
+
+        // its own client may cancel it, which fails the job
+        send_frames(
+            &client_socket,
+            &[b"", CMD_CANCEL_JOB.as_bytes(), job_id.as_bytes()],
+        );
+        broker
+            .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        let reply = recv_frames(&client_socket);
+        assert_eq!(reply[3], REP_499_CANCELLED.as_bytes());
+        let status = RenderReply::from_json(&reply[5]).unwrap().job.unwrap();
+        assert_eq!(status.state, JobState::Failed);
+        assert!(state.request_queue.is_empty());
+        let client_id = Identity::from("C1");
+        assert_eq!(
+            state.jobs.result(&job_id, &client_id).unwrap().code,
+            REP_499_CANCELLED
+        );
+
+        send_frames(
+            &client_socket,
+            &[b"", CMD_CANCEL_JOB.as_bytes(), job_id.as_bytes()],
+        );
+        broker
+            .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        assert_eq!(recv_frames(&client_socket)[3], REP_404_NOT_FOUND.as_bytes());
+    }
+
+    #[test]
+    fn refuse_jobs_once_results_pile_up() {
+        let (mut broker, _, context, frontend_socket, backend_socket) = bind_broker("refuse-job");
+        broker.config.max_finished_jobs = 1;
+        let mut state = BrokerState::new(&broker.config);
+        let client_socket = connect_peer(&context, &broker.config.frontend_endpoint, "C1");
+
+        send_frames(&client_socket, &[b"", CMD_SUBMIT.as_bytes(), b"{}"]);
+        broker
+            .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        let reply = recv_frames(&client_socket);
+        let job_id = RenderReply::from_json(&reply[5]).unwrap().job.unwrap().id;
+        send_frames(
+            &client_socket,
+            &[b"", CMD_CANCEL_JOB.as_bytes(), job_id.as_bytes()],
+        );
+        broker
+            .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        assert_eq!(recv_frames(&client_socket)[3], REP_499_CANCELLED.as_bytes());
+
+        // the cancelled job's result is kept, which is as many as allowed
+        send_frames(&client_socket, &[b"", CMD_SUBMIT.as_bytes(), b"{}"]);
+        broker
+            .handle_frontend_talking(&backend_socket, &frontend_socket, &mut state)
+            .unwrap();
+        assert_eq!(
+            recv_frames(&client_socket)[3],
+            REP_503_SERVICE_UNAVAILABLE.as_bytes()
+        );
     }
 }
@@ -0,0 +1,513 @@
+use super::error::ErrorKind;
+use super::helpers::get_random_u64;
+use super::protocol::*;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+use zmq;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_RETRIES: usize = 3;
+
+#[derive(Debug)]
+pub enum ClusterError {
+    // how long was waited, over how many attempts, is left to the caller to tell
+    Timeout {
+        endpoint: String,
+        waited: Duration,
+        attempts: usize,
+    },
+    Transport(zmq::Error),
+    Protocol(String),
+    Rejected {
+        code: String,
+        message: String,
+        stage: Option<RenderStage>,
+        kind: Option<ErrorKind>,
+        diagnostics: Option<Diagnostics>,
+    },
+}
+
+impl Error for ClusterError {}
+
+impl fmt::Display for ClusterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClusterError::Timeout {
+                endpoint,
+                waited,
+                attempts,
+            } => write!(
+                f,
+                "no reply from {} in {}ms ({} attempts)",
+                endpoint,
+                waited.as_millis(),
+                attempts
+            ),
+            ClusterError::Transport(reason) => write!(f, "transport failure: {}", reason),
+            ClusterError::Protocol(details) => write!(f, "protocol violation: {}", details),
+            ClusterError::Rejected {
+                code,
+                message,
+                stage: Some(stage),
+                ..
+            } => write!(f, "{} at {}: {}", code, stage, message),
+            ClusterError::Rejected { code, message, .. } => write!(f, "{}: {}", code, message),
+        }
+    }
+}
+
+impl From<zmq::Error> for ClusterError {
+    fn from(error: zmq::Error) -> Self {
+        ClusterError::Transport(error)
+    }
+}
+
+#[derive(Debug)]
+pub struct RenderedPdf {
+    pub worker_id: String,
+    pub reply: RenderReply,
+    pub pdf: Option<Vec<u8>>,
+}
+
+pub struct ClusterClient {
+    endpoint: String,
+    identity: String,
+    timeout: Duration,
+    retries: usize,
+    context: zmq::Context,
+    socket: Option<zmq::Socket>,
+}
+
+impl ClusterClient {
+    pub fn new(endpoint: &str, timeout: Duration, retries: usize) -> ClusterClient {
+        let instance = ClusterClient {
+            endpoint: endpoint.to_string(),
+            identity: format!("C{:016x}", get_random_u64()),
+            timeout: timeout,
+            retries: retries,
+            context: zmq::Context::new(),
+            socket: None,
+        };
+        instance
+    }
+
+    // A render may run for as long as its own timeout, on top of the usual wait
+    // for a reply, and sending it again meanwhile would have it rendered twice,
+    // so it is sent only once.
+    pub fn render(&mut self, request: &RenderRequest) -> Result<RenderedPdf, ClusterError> {
+        let wait = self.timeout + request.timeout(Duration::default());
+        let frames = self.exchange(&[request.to_json().as_bytes()], wait, 0)?;
+        decode_reply(frames)
+    }
+
+    // Submits a render as a job, to be fetched once done rather than waited for.
+    // A lost reply might be of a job submitted already, so neither is it resent.
+    pub fn submit(&mut self, request: &RenderRequest) -> Result<JobStatus, ClusterError> {
+        let frames = self.exchange(
+            &[CMD_SUBMIT.as_bytes(), request.to_json().as_bytes()],
+            self.timeout,
+            0,
+        )?;
+        decode_job_reply(frames)
+    }
+
+    pub fn status(&mut self, job_id: &str) -> Result<JobStatus, ClusterError> {
+        let frames = self.exchange(
+            &[CMD_STATUS.as_bytes(), job_id.as_bytes()],
+            self.timeout,
+            self.retries,
+        )?;
+        decode_job_reply(frames)
+    }
+
+    // Gives up on a job, which then fails with a 499 reply (or a 404, if it is
+    // finished already, or a 409, if a worker that cannot abort is rendering it).
+    pub fn cancel_job(&mut self, job_id: &str) -> Result<JobStatus, ClusterError> {
+        let frames = self.exchange(
+            &[CMD_CANCEL_JOB.as_bytes(), job_id.as_bytes()],
+            self.timeout,
+            self.retries,
+        )?;
+        decode_job_reply(frames)
+    }
+
+    // Result of a job, none until it is finished.
+    pub fn fetch(&mut self, job_id: &str) -> Result<Option<RenderedPdf>, ClusterError> {
+        let frames = self.exchange(
+            &[CMD_FETCH.as_bytes(), job_id.as_bytes()],
+            self.timeout,
+            self.retries,
+        )?;
+        if frames.len() > 2 && frames[2] == REP_202_ACCEPTED.as_bytes() {
+            return decode_job_reply(frames).map(|_| None);
+        }
+        decode_reply(frames).map(Some)
+    }
+
+    fn exchange(
+        &mut self,
+        request: &[&[u8]],
+        wait: Duration,
+        retries: usize,
+    ) -> Result<Vec<Vec<u8>>, ClusterError> {
+        for _ in 0..=retries {
+            if self.socket.is_none() {
+                self.socket = Some(self.connect()?);
+            }
+            let socket = self.socket.as_ref().unwrap();
+            socket.send_multipart(request, 0)?;
+
+            let mut poll_items = [socket.as_poll_item(zmq::POLLIN)];
+            zmq::poll(&mut poll_items, wait.as_millis() as i64)?;
+            if poll_items[0].is_readable() {
+                return Ok(socket.recv_multipart(0)?);
+            }
+
+            // a REQ socket is stuck once its reply is lost, so throw it away and
+            // retry on a fresh connection (aka lazy pirate)
+            self.socket = None;
+        }
+
+        Err(ClusterError::Timeout {
+            endpoint: self.endpoint.clone(),
+            waited: wait * (retries + 1) as u32,
+            attempts: retries + 1,
+        })
+    }
+
+    fn connect(&self) -> Result<zmq::Socket, ClusterError> {
+        // jobs are only known to the client that submitted them, so the identity
+        // is kept across reconnections rather than made up anew by ZMQ
+        let socket = self.context.socket(zmq::REQ)?;
+        socket.set_identity(self.identity.as_bytes())?;
+        socket.set_linger(0)?;
+        socket.connect(&self.endpoint)?;
+        Ok(socket)
+    }
+}
+
+impl Default for ClusterClient {
+    fn default() -> Self {
+        ClusterClient::new(DEFAULT_FRONTEND_ENDPOINT, DEFAULT_TIMEOUT, DEFAULT_RETRIES)
+    }
+}
+
+// Keeps many renders in flight on one DEALER socket, each under an ID of the
+// caller's choosing, and hands their replies back as they come, in any order.
+// Unlike with a REQ socket nothing gets stuck when a reply is lost, so there is
+// no retry: a request not replied to in time can simply be sent again.
+pub struct ClusterPipeline {
+    endpoint: String,
+    _context: zmq::Context,
+    socket: zmq::Socket,
+}
+
+impl ClusterPipeline {
+    pub fn connect(endpoint: &str) -> Result<ClusterPipeline, ClusterError> {
+        let context = zmq::Context::new();
+        let socket = context.socket(zmq::DEALER)?;
+        socket.set_linger(0)?;
+        socket.connect(endpoint)?;
+        let instance = ClusterPipeline {
+            endpoint: endpoint.to_string(),
+            _context: context,
+            socket: socket,
+        };
+        Ok(instance)
+    }
+
+    pub fn send(&self, request_id: &str, request: &RenderRequest) -> Result<(), ClusterError> {
+        // a DEALER socket leaves the <EMPTY> delimiter to us
+        let request = request.to_json();
+        let frames: [&[u8]; 4] = [
+            b"",
+            CMD_RENDER.as_bytes(),
+            request_id.as_bytes(),
+            request.as_bytes(),
+        ];
+        self.socket.send_multipart(&frames, 0)?;
+        Ok(())
+    }
+
+    // Gives up on a request, whose reply is then a 499 (or a 404, if it was
+    // replied to already). A request rendering on a worker the broker did not
+    // spawn cannot be, so a 409 comes first and its own reply later.
+    pub fn cancel(&self, request_id: &str) -> Result<(), ClusterError> {
+        let frames: [&[u8]; 3] = [b"", MSG_CANCEL.as_bytes(), request_id.as_bytes()];
+        self.socket.send_multipart(&frames, 0)?;
+        Ok(())
+    }
+
+    // Next reply to come, along with the ID of the request it belongs to.
+    pub fn recv(
+        &self,
+        timeout: Duration,
+    ) -> Result<(String, Result<RenderedPdf, ClusterError>), ClusterError> {
+        let mut poll_items = [self.socket.as_poll_item(zmq::POLLIN)];
+        zmq::poll(&mut poll_items, timeout.as_millis() as i64)?;
+        if !poll_items[0].is_readable() {
+            return Err(ClusterError::Timeout {
+                endpoint: self.endpoint.clone(),
+                waited: timeout,
+                attempts: 1,
+            });
+        }
+        decode_pipelined_reply(self.socket.recv_multipart(0)?)
+    }
+}
+
+fn decode_pipelined_reply(
+    mut frames: Vec<Vec<u8>>,
+) -> Result<(String, Result<RenderedPdf, ClusterError>), ClusterError> {
+    // reply envelope, as DEALER socket gets it:
+    //   EMPTY, REQUEST-ID, WORKER, EMPTY, REPLY, EMPTY, CONTENT[, PDF]
+    if frames.len() < 3 || !frames[0].is_empty() {
+        return Err(ClusterError::Protocol(String::from(
+            "missing <EMPTY> delimiter in reply",
+        )));
+    }
+
+    // a request so broken broker could not tell its ID is replied to without it
+    if frames[2].is_empty() {
+        let reason = match decode_reply(frames.split_off(1)) {
+            Ok(_) => String::from("reply has no <REQUEST-ID>"),
+            Err(reason) => format!("reply has no <REQUEST-ID>: {}", reason),
+        };
+        return Err(ClusterError::Protocol(reason));
+    }
+    let mut frames = frames.split_off(1);
+    let remaining = frames.split_off(1);
+    let request_id = decode_text(frames.pop().unwrap(), "REQUEST-ID")?;
+    Ok((request_id, decode_reply(remaining)))
+}
+
+fn decode_reply(frames: Vec<Vec<u8>>) -> Result<RenderedPdf, ClusterError> {
+    let (worker_id, content, pdf) = decode_envelope(frames)?;
+    if !content.is_success() {
+        return Err(rejected(content));
+    }
+
+    Ok(RenderedPdf {
+        worker_id: worker_id,
+        reply: content,
+        pdf: pdf,
+    })
+}
+
+fn decode_job_reply(frames: Vec<Vec<u8>>) -> Result<JobStatus, ClusterError> {
+    let (_, content, _) = decode_envelope(frames)?;
+    match content.job {
+        Some(status)
+            if content.code == REP_200_SUCCESS
+                || content.code == REP_202_ACCEPTED
+                || content.code == REP_499_CANCELLED =>
+        {
+            Ok(status)
+        }
+        _ => Err(rejected(content)),
+    }
+}
+
+fn decode_envelope(
+    frames: Vec<Vec<u8>>,
+) -> Result<(String, RenderReply, Option<Vec<u8>>), ClusterError> {
+    // reply envelope, once REQ socket strips CLIENT and EMPTY out:
+    //   WORKER, EMPTY, REPLY, EMPTY, CONTENT
+    //   WORKER, EMPTY, REPLY, EMPTY, CONTENT, PDF
+    if frames.len() < 5 || frames.len() > 6 {
+        return Err(ClusterError::Protocol(format!(
+            "unexpected number of frames in reply: {}",
+            frames.len()
+        )));
+    }
+    if !frames[1].is_empty() || !frames[3].is_empty() {
+        return Err(ClusterError::Protocol(String::from(
+            "missing <EMPTY> delimiter in reply",
+        )));
+    }
+
+    let mut frames = frames.into_iter();
+    let worker_id = decode_text(frames.next().unwrap(), "WORKER")?;
+    frames.next();
+    let reply = decode_text(frames.next().unwrap(), "REPLY")?;
+    frames.next();
+    let content = match RenderReply::from_json(&frames.next().unwrap()) {
+        Ok(parsed) => parsed,
+        Err(reason) => return Err(ClusterError::Protocol(reason.to_string())),
+    };
+    let pdf = frames.next();
+
+    if reply != content.code {
+        return Err(ClusterError::Protocol(format!(
+            "<REPLY> {} does not match <CONTENT> code {}",
+            reply, content.code
+        )));
+    }
+    Ok((worker_id, content, pdf))
+}
+
+fn rejected(content: RenderReply) -> ClusterError {
+    ClusterError::Rejected {
+        code: content.code,
+        message: content.message,
+        stage: content.stage,
+        kind: content.kind,
+        diagnostics: content.diagnostics,
+    }
+}
+
+fn decode_text(frame: Vec<u8>, name: &str) -> Result<String, ClusterError> {
+    String::from_utf8(frame)
+        .map_err(|_| ClusterError::Protocol(format!("<{}> of reply is not UTF-8", name)))
+}
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AnyError;
+
+    fn envelope(frames: &[&[u8]]) -> Vec<Vec<u8>> {
+        frames.iter().map(|frame| frame.to_vec()).collect()
+    }
+
+    #[test]
+    fn describe_timeouts() {
+        let reason = ClusterError::Timeout {
+            endpoint: String::from("tcp://127.0.0.1:6660"),
+            waited: Duration::from_secs(40),
+            attempts: 4,
+        };
+        assert_eq!(
+            reason.to_string(),
+            "no reply from tcp://127.0.0.1:6660 in 40000ms (4 attempts)"
+        );
+    }
+
+    #[test]
+    fn decode_reply_with_pdf() {
+        let content = RenderReply::success("PDF built", None, 8).to_json();
+        let frames = envelope(&[b"W1", b"", b"200", b"", content.as_bytes(), b"%PDF-1.4"]);
+        let rendered = decode_reply(frames).unwrap();
+        assert_eq!(rendered.worker_id, "W1");
+        assert_eq!(rendered.reply.message, "PDF built");
+        assert_eq!(rendered.reply.size, Some(8));
+        assert_eq!(rendered.pdf, Some(b"%PDF-1.4".to_vec()));
+    }
+
+    #[test]
+    fn decode_reply_with_error() {
+        let content = RenderReply::error(REP_400_BAD_REQUEST, "URL is missing").to_json();
+        let frames = envelope(&[b"W1", b"", b"400", b"", content.as_bytes()]);
+        match decode_reply(frames) {
+            Err(ClusterError::Rejected {
+                code,
+                message,
+                stage,
+                kind,
+                diagnostics,
+            }) => {
+                assert_eq!(code, REP_400_BAD_REQUEST);
+                assert_eq!(message, "URL is missing");
+                assert_eq!(stage, None);
+                assert_eq!(kind, None);
+                assert_eq!(diagnostics, None);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_reply_with_failure() {
+        let reason = AnyError::without_parent(ErrorKind::Render, "Host not found");
+        let content = RenderReply::failure(&reason, RenderStage::Convert).to_json();
+        let frames = envelope(&[b"W1", b"", b"422", b"", content.as_bytes()]);
+        let err = decode_reply(frames).unwrap_err();
+        assert_eq!(err.to_string(), "422 at convert: Host not found");
+        assert!(matches!(
+            err,
+            ClusterError::Rejected {
+                kind: Some(ErrorKind::Render),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn decode_job_replies() {
+        let status = JobStatus {
+            id: String::from("J1"),
+            state: JobState::Rendering,
+        };
+        let content = RenderReply::job(REP_200_SUCCESS, "Job is rendering", status.clone());
+        let frames = envelope(&[b"B1", b"", b"200", b"", content.to_json().as_bytes()]);
+        assert_eq!(decode_job_reply(frames).unwrap(), status);
+
+        let status = JobStatus {
+            state: JobState::Failed,
+            ..status
+        };
+        let content = RenderReply::job(REP_499_CANCELLED, "Job was cancelled", status.clone());
+        let frames = envelope(&[b"B1", b"", b"499", b"", content.to_json().as_bytes()]);
+        assert_eq!(decode_job_reply(frames).unwrap(), status);
+
+        let content = RenderReply::error(REP_404_NOT_FOUND, "No job J2, or it expired");
+        let frames = envelope(&[b"B1", b"", b"404", b"", content.to_json().as_bytes()]);
+        assert_eq!(
+            decode_job_reply(frames).unwrap_err().to_string(),
+            "404: No job J2, or it expired"
+        );
+    }
+
+    #[test]
+    fn decode_pipelined_replies() {
+        let content = RenderReply::success("PDF built", None, 8).to_json();
+        let frames = envelope(&[
+            b"",
+            b"R2",
+            b"W1",
+            b"",
+            b"200",
+            b"",
+            content.as_bytes(),
+            b"%PDF-1.4",
+        ]);
+        let (request_id, rendered) = decode_pipelined_reply(frames).unwrap();
+        assert_eq!(request_id, "R2");
+        assert_eq!(rendered.unwrap().pdf, Some(b"%PDF-1.4".to_vec()));
+
+        let content = RenderReply::error(REP_429_TOO_MANY_REQUESTS, "Slow down").to_json();
+        let frames = envelope(&[b"", b"R3", b"B1", b"", b"429", b"", content.as_bytes()]);
+        let (request_id, rendered) = decode_pipelined_reply(frames).unwrap();
+        assert_eq!(request_id, "R3");
+        assert_eq!(rendered.unwrap_err().to_string(), "429: Slow down");
+
+        let content = RenderReply::error(REP_400_BAD_REQUEST, "Request ID is not text").to_json();
+        let frames = envelope(&[b"", b"B1", b"", b"400", b"", content.as_bytes()]);
+        assert_eq!(
+            decode_pipelined_reply(frames).unwrap_err().to_string(),
+            "protocol violation: reply has no <REQUEST-ID>: 400: Request ID is not text"
+        );
+    }
+
+    #[test]
+    fn decode_truncated_reply() {
+        let frames = envelope(&[b"W1", b"", b"200"]);
+        assert!(matches!(
+            decode_reply(frames),
+            Err(ClusterError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn decode_reply_with_garbage_content() {
+        let frames = envelope(&[b"W1", b"", b"200", b"", b"PDF saved"]);
+        assert!(matches!(
+            decode_reply(frames),
+            Err(ClusterError::Protocol(_))
+        ));
+    }
+}
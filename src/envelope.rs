@@ -0,0 +1,532 @@
+use super::helpers::zmq_helpers::Identity;
+use super::protocol::*;
+use std::fmt;
+use zmq;
+
+// A multipart message that has the shape of no envelope the broker knows. It is
+// discarded as a whole; its sender, when there is one, is only told about it
+// when it can tell which request the reply goes to.
+#[derive(Debug)]
+pub struct Malformed {
+    pub sender: Option<Identity>,
+    pub can_reply: bool,
+    pub reason: String,
+    pub request_id: Option<String>,
+}
+
+impl Malformed {
+    fn new(sender: Option<Identity>, can_reply: bool, reason: &str) -> Malformed {
+        Malformed {
+            sender: sender,
+            can_reply: can_reply,
+            reason: reason.to_string(),
+            request_id: None,
+        }
+    }
+}
+
+// Where the reply to a request goes: back to the client that sent it, along
+// with the ID the client gave the request, if any, or, for a job, to the
+// broker, which keeps it until the client fetches it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyTo {
+    pub client_id: Identity,
+    pub request_id: Option<String>,
+    pub job_id: Option<String>,
+}
+
+impl ReplyTo {
+    pub fn client(client_id: &Identity) -> ReplyTo {
+        ReplyTo {
+            client_id: client_id.clone(),
+            request_id: None,
+            job_id: None,
+        }
+    }
+
+    pub fn request(client_id: &Identity, request_id: Option<&str>) -> ReplyTo {
+        ReplyTo {
+            request_id: request_id.map(String::from),
+            ..ReplyTo::client(client_id)
+        }
+    }
+
+    pub fn job(client_id: &Identity, job_id: &str) -> ReplyTo {
+        ReplyTo {
+            job_id: Some(job_id.to_string()),
+            ..ReplyTo::client(client_id)
+        }
+    }
+}
+
+impl fmt::Display for ReplyTo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.client_id)?;
+        if let Some(request_id) = &self.request_id {
+            write!(f, " (request {})", request_id)?;
+        }
+        if let Some(job_id) = &self.job_id {
+            write!(f, " (job {})", job_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientCommand {
+    Render(zmq::Message),
+    Submit(zmq::Message),
+    Status(String),
+    Fetch(String),
+    // of the request under the envelope's request ID
+    Cancel,
+    CancelJob(String),
+}
+
+// A request as it comes from a client, either waiting for its PDF or not. A
+// client with many renders in flight on one socket (e.g. DEALER) gives each an
+// ID, which goes back along with its reply:
+//   ID, EMPTY, REQUEST
+//   ID, EMPTY, RENDER, REQUEST-ID, REQUEST
+//   ID, EMPTY, SUBMIT, REQUEST
+//   ID, EMPTY, STATUS, JOB
+//   ID, EMPTY, FETCH, JOB
+//   ID, EMPTY, CANCEL, REQUEST-ID
+//   ID, EMPTY, CANCEL-JOB, JOB
+#[derive(Debug)]
+pub struct ClientEnvelope {
+    pub client_id: Identity,
+    pub request_id: Option<String>,
+    pub command: ClientCommand,
+}
+
+impl ClientEnvelope {
+    pub fn parse(
+        mut frames: Vec<zmq::Message>,
+        max_request_size: usize,
+    ) -> Result<ClientEnvelope, Malformed> {
+        if frames.is_empty() {
+            return Err(Malformed::new(None, false, "Envelope has no frames"));
+        }
+        let client_id = Identity::from(&frames[0][..]);
+        let malformed = |reason: &str| Malformed::new(Some(client_id.clone()), true, reason);
+
+        // REQ sockets only take replies after the <EMPTY> delimiter they sent
+        if frames.len() < 2 || !frames[1].is_empty() {
+            return Err(Malformed::new(
+                Some(client_id),
+                false,
+                "Envelope has no <EMPTY> delimiter",
+            ));
+        }
+        if frames.len() < 3 || frames.len() > 5 {
+            return Err(malformed(
+                format!("Envelope has {} frames instead of 3 to 5", frames.len()).as_str(),
+            ));
+        }
+
+        // a lone frame is the request itself, which is not worth copying as text
+        let name = match frames.len() {
+            3 => None,
+            _ => frames[2].as_str().map(String::from),
+        };
+        let mut request_id = None;
+        let command = match (name.as_deref(), frames.len()) {
+            (None, 3) => ClientCommand::Render(frames.pop().unwrap()),
+            (Some(CMD_RENDER), 5) => {
+                let request = frames.pop().unwrap();
+                request_id = Some(
+                    text_argument(&frames[3]).ok_or_else(|| malformed("Request ID is not text"))?,
+                );
+                ClientCommand::Render(request)
+            }
+            (Some(CMD_SUBMIT), 4) => ClientCommand::Submit(frames.pop().unwrap()),
+            (Some(MSG_CANCEL), 4) => {
+                request_id = Some(
+                    text_argument(&frames[3]).ok_or_else(|| malformed("Request ID is not text"))?,
+                );
+                ClientCommand::Cancel
+            }
+            (Some(CMD_STATUS), 4) | (Some(CMD_FETCH), 4) | (Some(CMD_CANCEL_JOB), 4) => {
+                let job_id =
+                    text_argument(&frames[3]).ok_or_else(|| malformed("Job ID is not text"))?;
+                match name.as_deref() {
+                    Some(CMD_STATUS) => ClientCommand::Status(job_id),
+                    Some(CMD_FETCH) => ClientCommand::Fetch(job_id),
+                    _ => ClientCommand::CancelJob(job_id),
+                }
+            }
+            (Some(name @ CMD_RENDER), _)
+            | (Some(name @ CMD_SUBMIT), _)
+            | (Some(name @ CMD_STATUS), _)
+            | (Some(name @ CMD_FETCH), _)
+            | (Some(name @ MSG_CANCEL), _)
+            | (Some(name @ CMD_CANCEL_JOB), _) => {
+                return Err(malformed(
+                    format!("{} envelope has {} frames", name, frames.len()).as_str(),
+                ))
+            }
+            _ => return Err(malformed("Unknown command")),
+        };
+        if let ClientCommand::Render(request) | ClientCommand::Submit(request) = &command {
+            if request.len() > max_request_size {
+                let mut malformed = malformed(
+                    format!("Request is larger than {} bytes", max_request_size).as_str(),
+                );
+                malformed.request_id = request_id;
+                return Err(malformed);
+            }
+        }
+        Ok(ClientEnvelope {
+            client_id: client_id,
+            request_id: request_id,
+            command: command,
+        })
+    }
+}
+
+fn text_argument(frame: &zmq::Message) -> Option<String> {
+    frame
+        .as_str()
+        .filter(|text| !text.is_empty())
+        .map(String::from)
+}
+
+#[derive(Debug)]
+pub enum WorkerMessage {
+    Ready(WorkerCapabilities),
+    Heartbeat,
+    Gone,
+    Reply {
+        client_id: Identity,
+        code: String,
+        content: zmq::Message,
+        attachments: Vec<zmq::Message>,
+    },
+}
+
+// A message as it comes from a worker:
+//   ID, EMPTY, READY
+//   ID, EMPTY, READY, CAPABILITIES
+//   ID, EMPTY, HEARTBEAT
+//   ID, EMPTY, GONE
+//   ID, EMPTY, CLIENT, EMPTY, REPLY, EMPTY, CONTENT
+//   ID, EMPTY, CLIENT, EMPTY, REPLY, EMPTY, CONTENT, PDF
+#[derive(Debug)]
+pub struct WorkerEnvelope {
+    pub worker_id: String,
+    pub message: WorkerMessage,
+}
+
+impl WorkerEnvelope {
+    pub fn parse(frames: Vec<zmq::Message>) -> Result<WorkerEnvelope, Malformed> {
+        if frames.is_empty() {
+            return Err(Malformed::new(None, false, "Envelope has no frames"));
+        }
+        let sender = Identity::from(&frames[0][..]);
+
+        // workers name themselves, always as text
+        let worker_id = match frames[0].as_str() {
+            Some(worker_id) => worker_id.to_string(),
+            None => return Err(Malformed::new(Some(sender), false, "Worker ID is not text")),
+        };
+        if frames.len() < 3 || !frames[1].is_empty() {
+            return Err(Malformed::new(
+                Some(sender),
+                false,
+                "Envelope has no <EMPTY> delimiter and message",
+            ));
+        }
+
+        let message = match frames[2].as_str() {
+            // workers that tell nothing about themselves are still welcome, but not
+            // ones telling gibberish
+            Some(MSG_WORKER_IS_READY) if frames.len() <= 4 => match frames.get(3) {
+                None => WorkerMessage::Ready(WorkerCapabilities::new("unknown", 0)),
+                Some(frame) => match WorkerCapabilities::from_json(frame) {
+                    Ok(capabilities) => WorkerMessage::Ready(capabilities),
+                    Err(_) => {
+                        return Err(Malformed::new(
+                            Some(sender),
+                            false,
+                            "Capabilities are not valid JSON",
+                        ))
+                    }
+                },
+            },
+            Some(MSG_HEARTBEAT) if frames.len() == 3 => WorkerMessage::Heartbeat,
+            Some(MSG_WORKER_IS_GONE) if frames.len() == 3 => WorkerMessage::Gone,
+            Some(command @ MSG_WORKER_IS_READY)
+            | Some(command @ MSG_HEARTBEAT)
+            | Some(command @ MSG_WORKER_IS_GONE) => {
+                return Err(Malformed::new(
+                    Some(sender.clone()),
+                    false,
+                    format!("{} envelope has {} frames", command, frames.len()).as_str(),
+                ))
+            }
+            // anything but a command is a reply, whose request the worker is done with
+            _ => WorkerEnvelope::parse_reply(&sender, frames)?,
+        };
+        Ok(WorkerEnvelope {
+            worker_id: worker_id,
+            message: message,
+        })
+    }
+
+    fn parse_reply(
+        sender: &Identity,
+        frames: Vec<zmq::Message>,
+    ) -> Result<WorkerMessage, Malformed> {
+        let malformed = |reason: &str| Malformed::new(Some(sender.clone()), true, reason);
+        if frames.len() < 7 || frames.len() > 8 {
+            return Err(malformed(
+                format!("Reply has {} frames instead of 7 or 8", frames.len()).as_str(),
+            ));
+        }
+        if !frames[3].is_empty() || !frames[5].is_empty() {
+            return Err(malformed("Reply has no <EMPTY> delimiter"));
+        }
+        let code = match frames[4].as_str() {
+            Some(code) if !code.is_empty() => code.to_string(),
+            _ => return Err(malformed("Reply code is not text")),
+        };
+
+        // content and whatever comes after it (i.e. PDF) might be binary, so
+        // their frames are kept untouched, to be forwarded without copying them
+        let mut frames = frames.into_iter();
+        let client_id = Identity::from(frames.nth(2).unwrap());
+        let content = frames.nth(3).unwrap();
+        Ok(WorkerMessage::Reply {
+            client_id: client_id,
+            code: code,
+            content: content,
+            attachments: frames.collect(),
+        })
+    }
+}
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_REQUEST_SIZE: usize = 64;
+
+    fn frames(parts: &[&[u8]]) -> Vec<zmq::Message> {
+        parts.iter().map(|part| zmq::Message::from(*part)).collect()
+    }
+
+    #[test]
+    fn parse_client_request() {
+        let binary_id: &[u8] = &[0, 0x80, 0, 0x41, 0xa7];
+        let envelope =
+            ClientEnvelope::parse(frames(&[binary_id, b"", b"{}"]), MAX_REQUEST_SIZE).unwrap();
+        assert_eq!(envelope.client_id, Identity::from(binary_id));
+        assert!(envelope.request_id.is_none());
+        match envelope.command {
+            ClientCommand::Render(request) => assert_eq!(&request[..], b"{}"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_pipelined_client_request() {
+        let envelope = ClientEnvelope::parse(
+            frames(&[b"C1", b"", b"RENDER", b"R42", b"{}"]),
+            MAX_REQUEST_SIZE,
+        )
+        .unwrap();
+        assert_eq!(envelope.request_id, Some(String::from("R42")));
+        assert!(matches!(envelope.command, ClientCommand::Render(_)));
+
+        let malformed = ClientEnvelope::parse(
+            frames(&[b"C1", b"", b"RENDER", b"", b"{}"]),
+            MAX_REQUEST_SIZE,
+        )
+        .unwrap_err();
+        assert_eq!(malformed.reason, "Request ID is not text");
+        assert!(malformed.can_reply);
+
+        let malformed =
+            ClientEnvelope::parse(frames(&[b"C1", b"", b"RENDER", b"{}"]), MAX_REQUEST_SIZE)
+                .unwrap_err();
+        assert_eq!(malformed.reason, "RENDER envelope has 4 frames");
+    }
+
+    #[test]
+    fn parse_client_commands() {
+        let envelope =
+            ClientEnvelope::parse(frames(&[b"C1", b"", b"SUBMIT", b"{}"]), MAX_REQUEST_SIZE)
+                .unwrap();
+        assert!(matches!(envelope.command, ClientCommand::Submit(_)));
+
+        let envelope =
+            ClientEnvelope::parse(frames(&[b"C1", b"", b"STATUS", b"J1"]), MAX_REQUEST_SIZE)
+                .unwrap();
+        assert!(matches!(envelope.command, ClientCommand::Status(job_id) if job_id == "J1"));
+
+        let envelope =
+            ClientEnvelope::parse(frames(&[b"C1", b"", b"FETCH", b"J1"]), MAX_REQUEST_SIZE)
+                .unwrap();
+        assert!(matches!(envelope.command, ClientCommand::Fetch(job_id) if job_id == "J1"));
+
+        let envelope =
+            ClientEnvelope::parse(frames(&[b"C1", b"", b"CANCEL", b"R42"]), MAX_REQUEST_SIZE)
+                .unwrap();
+        assert!(matches!(envelope.command, ClientCommand::Cancel));
+        assert_eq!(envelope.request_id, Some(String::from("R42")));
+
+        let envelope = ClientEnvelope::parse(
+            frames(&[b"C1", b"", b"CANCEL-JOB", b"J1"]),
+            MAX_REQUEST_SIZE,
+        )
+        .unwrap();
+        assert!(matches!(envelope.command, ClientCommand::CancelJob(job_id) if job_id == "J1"));
+        assert!(envelope.request_id.is_none());
+
+        let malformed =
+            ClientEnvelope::parse(frames(&[b"C1", b"", b"FETCH", b"\xff"]), MAX_REQUEST_SIZE)
+                .unwrap_err();
+        assert_eq!(malformed.reason, "Job ID is not text");
+        assert!(malformed.can_reply);
+    }
+
+    #[test]
+    fn discard_truncated_client_envelopes() {
+        let malformed = ClientEnvelope::parse(Vec::new(), MAX_REQUEST_SIZE).unwrap_err();
+        assert!(malformed.sender.is_none());
+        assert!(!malformed.can_reply);
+
+        let malformed = ClientEnvelope::parse(frames(&[b"C1"]), MAX_REQUEST_SIZE).unwrap_err();
+        assert_eq!(malformed.sender, Some(Identity::from("C1")));
+        assert!(!malformed.can_reply);
+
+        let malformed = ClientEnvelope::parse(frames(&[b"C1", b""]), MAX_REQUEST_SIZE).unwrap_err();
+        assert!(malformed.can_reply);
+    }
+
+    #[test]
+    fn discard_oversized_client_envelopes() {
+        let malformed = ClientEnvelope::parse(
+            frames(&[b"C1", b"", b"SUBMIT", b"{}", b"{}"]),
+            MAX_REQUEST_SIZE,
+        )
+        .unwrap_err();
+        assert!(malformed.can_reply);
+        assert_eq!(malformed.reason, "SUBMIT envelope has 5 frames");
+
+        let malformed = ClientEnvelope::parse(
+            frames(&[b"C1", b"", b"RENDER", b"R1", b"{}", b"{}"]),
+            MAX_REQUEST_SIZE,
+        )
+        .unwrap_err();
+        assert_eq!(malformed.reason, "Envelope has 6 frames instead of 3 to 5");
+
+        let request = vec![b' '; MAX_REQUEST_SIZE + 1];
+        let malformed =
+            ClientEnvelope::parse(frames(&[b"C1", b"", &request]), MAX_REQUEST_SIZE).unwrap_err();
+        assert!(malformed.can_reply);
+        assert_eq!(malformed.reason, "Request is larger than 64 bytes");
+
+        // a pipelined client still gets to know which of its requests it was
+        let malformed = ClientEnvelope::parse(
+            frames(&[b"C1", b"", b"RENDER", b"R7", &request]),
+            MAX_REQUEST_SIZE,
+        )
+        .unwrap_err();
+        assert_eq!(malformed.request_id, Some(String::from("R7")));
+    }
+
+    #[test]
+    fn discard_garbage_client_envelopes() {
+        let malformed =
+            ClientEnvelope::parse(frames(&[b"C1", b"\xff\xfe", b"{}"]), MAX_REQUEST_SIZE)
+                .unwrap_err();
+        assert!(!malformed.can_reply);
+
+        let malformed =
+            ClientEnvelope::parse(frames(&[b"C1", b"", b"\xff\xfe", b"{}"]), MAX_REQUEST_SIZE)
+                .unwrap_err();
+        assert_eq!(malformed.reason, "Unknown command");
+        assert!(malformed.can_reply);
+    }
+
+    #[test]
+    fn parse_worker_commands() {
+        let capabilities = WorkerCapabilities::new("localhost", 42).to_json();
+        let envelope =
+            WorkerEnvelope::parse(frames(&[b"W1", b"", b"READY", capabilities.as_bytes()]))
+                .unwrap();
+        assert_eq!(envelope.worker_id, "W1");
+        match envelope.message {
+            WorkerMessage::Ready(capabilities) => assert_eq!(capabilities.pid, 42),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let envelope = WorkerEnvelope::parse(frames(&[b"W1", b"", b"HEARTBEAT"])).unwrap();
+        assert!(matches!(envelope.message, WorkerMessage::Heartbeat));
+        let envelope = WorkerEnvelope::parse(frames(&[b"W1", b"", b"GONE"])).unwrap();
+        assert!(matches!(envelope.message, WorkerMessage::Gone));
+    }
+
+    #[test]
+    fn parse_worker_replies() {
+        let binary_id: &[u8] = &[0, 0x80, 0, 0x41, 0xa7];
+        let envelope = WorkerEnvelope::parse(frames(&[
+            b"W1", b"", binary_id, b"", b"200", b"", b"{}", b"%PDF",
+        ]))
+        .unwrap();
+        match envelope.message {
+            WorkerMessage::Reply {
+                client_id,
+                code,
+                content,
+                attachments,
+            } => {
+                assert_eq!(client_id, Identity::from(binary_id));
+                assert_eq!(code, "200");
+                assert_eq!(&content[..], b"{}");
+                assert_eq!(attachments.len(), 1);
+                assert_eq!(&attachments[0][..], b"%PDF");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn discard_malformed_worker_envelopes() {
+        let malformed = WorkerEnvelope::parse(frames(&[b"W1", b""])).unwrap_err();
+        assert!(!malformed.can_reply);
+
+        let malformed =
+            WorkerEnvelope::parse(frames(&[b"W1", b"", b"HEARTBEAT", b"extra"])).unwrap_err();
+        assert!(!malformed.can_reply);
+
+        let malformed =
+            WorkerEnvelope::parse(frames(&[b"W1", b"", b"READY", b"garbage"])).unwrap_err();
+        assert_eq!(malformed.reason, "Capabilities are not valid JSON");
+
+        let malformed = WorkerEnvelope::parse(frames(&[b"\xff", b"", b"GONE"])).unwrap_err();
+        assert_eq!(malformed.reason, "Worker ID is not text");
+
+        // a broken reply still tells which worker is done with its request
+        let malformed =
+            WorkerEnvelope::parse(frames(&[b"W1", b"", b"C1", b"", b"200"])).unwrap_err();
+        assert_eq!(malformed.sender, Some(Identity::from("W1")));
+        assert!(malformed.can_reply);
+
+        let malformed =
+            WorkerEnvelope::parse(frames(&[b"W1", b"", b"C1", b"", b"\xff", b"", b"{}"]))
+                .unwrap_err();
+        assert_eq!(malformed.reason, "Reply code is not text");
+
+        let malformed = WorkerEnvelope::parse(frames(&[
+            b"W1", b"", b"C1", b"", b"200", b"", b"{}", b"%PDF", b"extra",
+        ]))
+        .unwrap_err();
+        assert_eq!(malformed.reason, "Reply has 9 frames instead of 7 or 8");
+    }
+}
@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fmt;
 use std::io;
@@ -10,22 +11,68 @@ type OptError = Option<DynError>;
 
 pub type Result<T> = std::result::Result<T, AnyError>;
 
+// What went wrong, so callers and supervisors can act on it rather than on the
+// text of the error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorKind {
+    Config,
+    Transport,
+    Protocol,
+    Validation,
+    Render,
+    Io,
+    Timeout,
+}
+
+impl ErrorKind {
+    // Process exit codes follow sysexits.h, so supervisors can tell e.g. a bad
+    // command line (not worth restarting) from a broker out of reach.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Config => 78,
+            ErrorKind::Transport => 69,
+            ErrorKind::Protocol => 76,
+            ErrorKind::Validation => 65,
+            ErrorKind::Render => 70,
+            ErrorKind::Io => 74,
+            ErrorKind::Timeout => 75,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Config => write!(f, "config"),
+            ErrorKind::Transport => write!(f, "transport"),
+            ErrorKind::Protocol => write!(f, "protocol"),
+            ErrorKind::Validation => write!(f, "validation"),
+            ErrorKind::Render => write!(f, "render"),
+            ErrorKind::Io => write!(f, "io"),
+            ErrorKind::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AnyError {
+    pub kind: ErrorKind,
     pub details: String,
     pub parent: OptError,
 }
 
 impl AnyError {
-    pub fn new(details: &str, reason: OptError) -> Self {
+    pub fn new(kind: ErrorKind, details: &str, reason: OptError) -> Self {
         AnyError {
+            kind: kind,
             details: details.to_string(),
             parent: reason,
         }
     }
 
-    pub fn without_parent(details: &str) -> Self {
-        AnyError::new(details, None)
+    pub fn without_parent(kind: ErrorKind, details: &str) -> Self {
+        AnyError::new(kind, details, None)
     }
 }
 
@@ -42,25 +89,68 @@ impl fmt::Display for AnyError {
 
 impl From<io::Error> for AnyError {
     fn from(error: io::Error) -> Self {
-        AnyError::new(&error.to_string(), Some(Box::new(error)))
+        let kind = match error.kind() {
+            io::ErrorKind::TimedOut => ErrorKind::Timeout,
+            _ => ErrorKind::Io,
+        };
+        AnyError::new(kind, &error.to_string(), Some(Box::new(error)))
     }
 }
 
 impl From<zmq::Error> for AnyError {
     fn from(error: zmq::Error) -> Self {
-        AnyError::new(&error.to_string(), Some(Box::new(error)))
+        let kind = match error {
+            zmq::Error::EAGAIN => ErrorKind::Timeout,
+            _ => ErrorKind::Transport,
+        };
+        AnyError::new(kind, &error.to_string(), Some(Box::new(error)))
     }
 }
 
-pub fn error<T, U: 'static + Error + Send>(message: &str, reason: U) -> Result<T> {
-    Err(AnyError::new(&message, Some(Box::new(reason))))
+pub fn error<T, U: 'static + Error + Send>(kind: ErrorKind, message: &str, reason: U) -> Result<T> {
+    Err(AnyError::new(kind, &message, Some(Box::new(reason))))
 }
 
-pub fn error_without_parent<T>(message: &str) -> Result<T> {
-    Err(AnyError::without_parent(&message))
+pub fn error_without_parent<T>(kind: ErrorKind, message: &str) -> Result<T> {
+    Err(AnyError::without_parent(kind, &message))
 }
 
-pub fn exit_with_error<U: 'static + Error>(message: &str, reason: U) {
-    eprintln!("{}: {}", message, reason);
-    process::exit(666);
+pub fn exit_with_error(message: &str, reason: &AnyError) -> ! {
+    eprintln!("{}: [{}] {}", message, reason.kind, reason);
+    process::exit(reason.kind.exit_code());
+}
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kind_of_converted_errors() {
+        let timed_out = io::Error::new(io::ErrorKind::TimedOut, "too slow");
+        assert_eq!(AnyError::from(timed_out).kind, ErrorKind::Timeout);
+        let not_found = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        assert_eq!(AnyError::from(not_found).kind, ErrorKind::Io);
+        assert_eq!(AnyError::from(zmq::Error::EAGAIN).kind, ErrorKind::Timeout);
+        assert_eq!(AnyError::from(zmq::Error::ETERM).kind, ErrorKind::Transport);
+    }
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let kinds = [
+            ErrorKind::Config,
+            ErrorKind::Transport,
+            ErrorKind::Protocol,
+            ErrorKind::Validation,
+            ErrorKind::Render,
+            ErrorKind::Io,
+            ErrorKind::Timeout,
+        ];
+        let mut codes: Vec<i32> = kinds.iter().map(|kind| kind.exit_code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), kinds.len());
+    }
 }
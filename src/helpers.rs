@@ -1,3 +1,8 @@
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::process;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn get_uid() -> u64 {
@@ -10,10 +15,34 @@ pub fn get_uid() -> u64 {
     time_in_ms
 }
 
+// Random enough not to be guessed: every RandomState is seeded apart by std.
+pub fn get_random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    hasher.write_u32(process::id());
+    hasher.finish()
+}
+
+pub fn get_hostname() -> String {
+    for name in &["HOSTNAME", "COMPUTERNAME"] {
+        if let Ok(hostname) = env::var(name) {
+            return hostname;
+        }
+    }
+    match fs::read_to_string("/etc/hostname") {
+        Ok(hostname) if !hostname.trim().is_empty() => String::from(hostname.trim()),
+        _ => String::from("localhost"),
+    }
+}
+
 pub mod fs_helpers {
+    use super::get_random_u64;
+    use std::env;
     use std::fs;
     use std::io;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
 
     pub fn create_dir_if_not_exists(output_dir: &Path) -> io::Result<()> {
         if output_dir.is_dir() {
@@ -21,21 +50,131 @@ pub mod fs_helpers {
         }
         fs::create_dir_all(output_dir)
     }
+
+    // Directory under the system's temp dir, only accessible by its owner, that is
+    // removed along with everything in it once dropped. Its name ends with a
+    // random suffix and it must not exist yet, so a directory or symlink planted
+    // there by someone else is never written into.
+    #[derive(Debug)]
+    pub struct TempDir {
+        path: PathBuf,
+    }
+
+    impl TempDir {
+        pub fn new(prefix: &str) -> io::Result<TempDir> {
+            let path = env::temp_dir().join(format!("{}-{:016x}", prefix, get_random_u64()));
+            let mut builder = fs::DirBuilder::new();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::DirBuilderExt;
+                builder.mode(0o700);
+            }
+            builder.create(&path)?;
+            Ok(TempDir { path: path })
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        pub fn write(&self, name: &str, content: &[u8]) -> io::Result<PathBuf> {
+            let filepath = self.path.join(name);
+            if let Some(parent) = filepath.parent() {
+                create_dir_if_not_exists(parent)?;
+            }
+            fs::write(&filepath, content)?;
+            Ok(filepath)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            if let Err(reason) = fs::remove_dir_all(&self.path) {
+                // nowhere to return it, and stdout is the application's
+                eprintln!("Failed to remove {}: {}", self.path.display(), reason);
+            }
+        }
+    }
 }
 
 pub mod zmq_helpers {
+    use std::fmt;
     use zmq;
 
-    pub fn assert_empty(socket: &zmq::Socket, expect_message: &str) {
-        assert!(socket
-            .recv_string(0)
-            .expect(expect_message)
-            .unwrap()
-            .is_empty());
+    // Identity of a peer as a ROUTER socket tells it: whatever the peer set or,
+    // unless it did, 5 binary bytes made up by ZMQ. It is kept as is, and only
+    // turned into text (hex when not printable) for logging.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct Identity(Vec<u8>);
+
+    impl Identity {
+        pub fn as_bytes(&self) -> &[u8] {
+            &self.0
+        }
     }
 
-    pub fn recv_string(socket: &zmq::Socket, expect_message: &str) -> String {
-        socket.recv_string(0).expect(expect_message).unwrap()
+    impl From<&[u8]> for Identity {
+        fn from(bytes: &[u8]) -> Self {
+            Identity(bytes.to_vec())
+        }
+    }
+
+    impl From<Vec<u8>> for Identity {
+        fn from(bytes: Vec<u8>) -> Self {
+            Identity(bytes)
+        }
+    }
+
+    impl From<&str> for Identity {
+        fn from(text: &str) -> Self {
+            Identity(text.as_bytes().to_vec())
+        }
+    }
+
+    impl From<zmq::Message> for Identity {
+        fn from(message: zmq::Message) -> Self {
+            Identity(message.to_vec())
+        }
+    }
+
+    impl From<Identity> for zmq::Message {
+        fn from(identity: Identity) -> Self {
+            zmq::Message::from(identity.0)
+        }
+    }
+
+    impl fmt::Display for Identity {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            if !self.0.is_empty() && self.0.iter().all(|byte| byte.is_ascii_graphic()) {
+                return write!(f, "{}", String::from_utf8_lossy(&self.0));
+            }
+            write!(f, "0x")?;
+            for byte in &self.0 {
+                write!(f, "{:02x}", byte)?;
+            }
+            Ok(())
+        }
+    }
+
+    // Turns an endpoint a socket binds to into one that local peers can connect
+    // to, i.e. wildcard addresses become loopback.
+    pub fn connect_endpoint(bind_endpoint: &str) -> String {
+        for wildcard in &["tcp://*:", "tcp://0.0.0.0:"] {
+            if bind_endpoint.starts_with(wildcard) {
+                return format!("tcp://127.0.0.1:{}", &bind_endpoint[wildcard.len()..]);
+            }
+        }
+        String::from(bind_endpoint)
+    }
+
+    // Reads every frame of the next message, whatever its shape, so a malformed
+    // one is drained as a whole rather than left to be taken for the next one.
+    pub fn recv_multipart(socket: &zmq::Socket) -> zmq::Result<Vec<zmq::Message>> {
+        let mut frames = vec![socket.recv_msg(0)?];
+        while socket.get_rcvmore()? {
+            frames.push(socket.recv_msg(0)?);
+        }
+        Ok(frames)
     }
 
     pub fn send<T: zmq::Sendable>(socket: &zmq::Socket, data: T, expect_message: &str) {
@@ -50,3 +189,60 @@ pub mod zmq_helpers {
         socket.send_multipart(frames, 0).expect(expect_message);
     }
 }
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::fs_helpers::TempDir;
+    use super::zmq_helpers::{connect_endpoint, Identity};
+
+    #[test]
+    fn connect_to_wildcard_endpoint() {
+        assert_eq!(connect_endpoint("tcp://*:6661"), "tcp://127.0.0.1:6661");
+        assert_eq!(
+            connect_endpoint("tcp://0.0.0.0:6661"),
+            "tcp://127.0.0.1:6661"
+        );
+        assert_eq!(
+            connect_endpoint("tcp://10.0.0.5:6661"),
+            "tcp://10.0.0.5:6661"
+        );
+        assert_eq!(
+            connect_endpoint("ipc:///tmp/wk-backend"),
+            "ipc:///tmp/wk-backend"
+        );
+    }
+
+    #[test]
+    fn create_private_temp_dirs() {
+        let first = TempDir::new("wk-test").unwrap();
+        let second = TempDir::new("wk-test").unwrap();
+        assert_ne!(first.path(), second.path());
+        assert!(first.path().is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = first.path().metadata().unwrap().permissions().mode();
+            assert_eq!(mode & 0o077, 0);
+        }
+
+        let path = first.path().to_path_buf();
+        first.write("assets/logo.svg", b"<svg/>").unwrap();
+        drop(first);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn display_identities() {
+        assert_eq!(
+            Identity::from("C42-1600000000000").to_string(),
+            "C42-1600000000000"
+        );
+        assert_eq!(
+            Identity::from(&[0u8, 0x80, 0x00, 0x41, 0xa7][..]).to_string(),
+            "0x00800041a7"
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use super::envelope::ReplyTo;
+use super::helpers::get_random_u64;
+use super::helpers::zmq_helpers::Identity;
+use super::protocol::{JobState, JobStatus, REP_200_SUCCESS};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// What a job ended with, as the reply a client waiting for it would have got.
+#[derive(Debug, Clone)]
+pub struct JobResult {
+    pub worker_id: String,
+    pub code: String,
+    pub content: Vec<u8>,
+    pub attachments: Vec<Vec<u8>>,
+}
+
+impl JobResult {
+    fn size(&self) -> usize {
+        self.content.len()
+            + self
+                .attachments
+                .iter()
+                .map(|attachment| attachment.len())
+                .sum::<usize>()
+    }
+}
+
+#[derive(Debug)]
+struct Job {
+    client_id: Identity,
+    state: JobState,
+    result: Option<JobResult>,
+    finished_at: Option<Instant>,
+}
+
+// Jobs submitted by clients that do not wait for their PDF. Results are kept
+// for a while once finished, so they can be fetched (even more than once)
+// until then, and are gone afterwards. A job is only known to the client that
+// submitted it, under an ID too random to be guessed, and results kept are
+// limited in number and bytes, past which no more jobs are taken.
+#[derive(Debug)]
+pub struct JobTable {
+    jobs: HashMap<String, Job>,
+    ttl: Duration,
+    max_finished: usize,
+    max_finished_bytes: usize,
+    finished: usize,
+    finished_bytes: usize,
+}
+
+impl JobTable {
+    pub fn new(ttl: Duration, max_finished: usize, max_finished_bytes: usize) -> JobTable {
+        let instance = JobTable {
+            jobs: HashMap::new(),
+            ttl: ttl,
+            max_finished: max_finished,
+            max_finished_bytes: max_finished_bytes,
+            finished: 0,
+            finished_bytes: 0,
+        };
+        instance
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    // Whether results kept reached either limit, in which case jobs still to
+    // finish are let through, so limits may be overshot by as many.
+    pub fn is_full(&self) -> bool {
+        self.finished >= self.max_finished || self.finished_bytes >= self.max_finished_bytes
+    }
+
+    pub fn submit(&mut self, client_id: &Identity) -> String {
+        let mut job_id = new_job_id();
+        while self.jobs.contains_key(&job_id) {
+            job_id = new_job_id();
+        }
+        self.jobs.insert(
+            job_id.clone(),
+            Job {
+                client_id: client_id.clone(),
+                state: JobState::Queued,
+                result: None,
+                finished_at: None,
+            },
+        );
+        job_id
+    }
+
+    pub fn forget(&mut self, job_id: &str) {
+        if let Some(job) = self.jobs.remove(job_id) {
+            self.forget_result(&job);
+        }
+    }
+
+    // Unfinished jobs go from queued to rendering and back, e.g. when the
+    // worker rendering one is lost.
+    pub fn set_state(&mut self, job_id: &str, state: JobState) {
+        if let Some(job) = self.unfinished(job_id) {
+            job.state = state;
+        }
+    }
+
+    pub fn finish(&mut self, job_id: &str, result: JobResult, now: Instant) {
+        if let Some(job) = self.unfinished(job_id) {
+            if result.code == REP_200_SUCCESS {
+                job.state = JobState::Done;
+            } else {
+                job.state = JobState::Failed;
+            }
+            job.result = Some(result);
+            job.finished_at = Some(now);
+        } else {
+            return;
+        }
+        self.finished += 1;
+        self.finished_bytes += self.jobs[job_id].result.as_ref().map_or(0, JobResult::size);
+    }
+
+    // Jobs of other clients are none of the asking client's business, so they
+    // look just like unknown ones.
+    pub fn status(&self, job_id: &str, client_id: &Identity) -> Option<JobStatus> {
+        self.owned(job_id, client_id).map(|job| JobStatus {
+            id: job_id.to_string(),
+            state: job.state,
+        })
+    }
+
+    // Where the reply of a job still to finish goes, so it can be told apart
+    // from any other request, e.g. to cancel it.
+    pub fn reply_to(&self, job_id: &str, client_id: &Identity) -> Option<ReplyTo> {
+        self.owned(job_id, client_id)
+            .filter(|job| job.result.is_none())
+            .map(|job| ReplyTo::job(&job.client_id, job_id))
+    }
+
+    pub fn result(&self, job_id: &str, client_id: &Identity) -> Option<&JobResult> {
+        self.owned(job_id, client_id)
+            .and_then(|job| job.result.as_ref())
+    }
+
+    pub fn purge_expired(&mut self, now: Instant) -> usize {
+        let ttl = self.ttl;
+        let expired: Vec<String> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| {
+                job.finished_at.map_or(false, |finished_at| {
+                    now.saturating_duration_since(finished_at) >= ttl
+                })
+            })
+            .map(|(job_id, _)| job_id.clone())
+            .collect();
+        for job_id in &expired {
+            self.forget(job_id);
+        }
+        expired.len()
+    }
+
+    fn owned(&self, job_id: &str, client_id: &Identity) -> Option<&Job> {
+        self.jobs
+            .get(job_id)
+            .filter(|job| job.client_id == *client_id)
+    }
+
+    fn unfinished(&mut self, job_id: &str) -> Option<&mut Job> {
+        self.jobs.get_mut(job_id).filter(|job| job.result.is_none())
+    }
+
+    fn forget_result(&mut self, job: &Job) {
+        if let Some(result) = &job.result {
+            self.finished -= 1;
+            self.finished_bytes -= result.size();
+        }
+    }
+}
+
+// 128 random bits, so a job's ID is as good as a password to get its PDF.
+fn new_job_id() -> String {
+    format!("J{:016x}{:016x}", get_random_u64(), get_random_u64())
+}
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> Identity {
+        Identity::from("C1")
+    }
+
+    fn table() -> JobTable {
+        JobTable::new(Duration::from_secs(60), 2, 1024)
+    }
+
+    fn result(code: &str) -> JobResult {
+        JobResult {
+            worker_id: String::from("W1"),
+            code: code.to_string(),
+            content: b"{}".to_vec(),
+            attachments: vec![b"%PDF".to_vec()],
+        }
+    }
+
+    #[test]
+    fn follow_job_until_done() {
+        let mut jobs = table();
+        let job_id = jobs.submit(&client());
+        assert_eq!(
+            jobs.status(&job_id, &client()).unwrap().state,
+            JobState::Queued
+        );
+        assert!(jobs.result(&job_id, &client()).is_none());
+
+        jobs.set_state(&job_id, JobState::Rendering);
+        assert_eq!(
+            jobs.status(&job_id, &client()).unwrap().state,
+            JobState::Rendering
+        );
+        assert_eq!(
+            jobs.reply_to(&job_id, &client()),
+            Some(ReplyTo::job(&client(), &job_id))
+        );
+
+        jobs.finish(&job_id, result("200"), Instant::now());
+        assert_eq!(
+            jobs.status(&job_id, &client()).unwrap().state,
+            JobState::Done
+        );
+        assert!(jobs.reply_to(&job_id, &client()).is_none());
+        assert_eq!(
+            jobs.result(&job_id, &client()).unwrap().attachments.len(),
+            1
+        );
+
+        // once finished, a job is what it is
+        jobs.set_state(&job_id, JobState::Queued);
+        jobs.finish(&job_id, result("502"), Instant::now());
+        assert_eq!(
+            jobs.status(&job_id, &client()).unwrap().state,
+            JobState::Done
+        );
+    }
+
+    #[test]
+    fn fail_job_on_error_reply() {
+        let mut jobs = table();
+        let job_id = jobs.submit(&client());
+        jobs.finish(&job_id, result("422"), Instant::now());
+        assert_eq!(
+            jobs.status(&job_id, &client()).unwrap().state,
+            JobState::Failed
+        );
+        assert_eq!(jobs.result(&job_id, &client()).unwrap().code, "422");
+    }
+
+    #[test]
+    fn give_unique_ids() {
+        let mut jobs = table();
+        assert_ne!(jobs.submit(&client()), jobs.submit(&client()));
+        assert_eq!(jobs.len(), 2);
+        assert!(jobs.status("J0-0", &client()).is_none());
+    }
+
+    #[test]
+    fn know_jobs_only_to_their_client() {
+        let mut jobs = table();
+        let job_id = jobs.submit(&client());
+        assert_eq!(job_id.len(), 33);
+        let other = Identity::from("C2");
+        assert!(jobs.status(&job_id, &other).is_none());
+        assert!(jobs.reply_to(&job_id, &other).is_none());
+
+        jobs.finish(&job_id, result("200"), Instant::now());
+        assert!(jobs.result(&job_id, &other).is_none());
+        assert!(jobs.result(&job_id, &client()).is_some());
+    }
+
+    #[test]
+    fn fill_up_with_results() {
+        let mut jobs = table();
+        let first = jobs.submit(&client());
+        let second = jobs.submit(&client());
+        let now = Instant::now();
+        jobs.finish(&first, result("200"), now);
+        assert!(!jobs.is_full());
+        jobs.finish(&second, result("200"), now);
+        assert!(jobs.is_full());
+
+        jobs.forget(&second);
+        assert!(!jobs.is_full());
+
+        // results too large fill it up as well
+        let large = jobs.submit(&client());
+        let mut large_result = result("200");
+        large_result.attachments = vec![vec![0; 1024]];
+        jobs.finish(&large, large_result, now);
+        assert!(jobs.is_full());
+        assert_eq!(jobs.purge_expired(now + Duration::from_secs(61)), 2);
+        assert!(!jobs.is_full());
+    }
+
+    #[test]
+    fn purge_finished_jobs_after_ttl() {
+        let mut jobs = table();
+        let finished = jobs.submit(&client());
+        let unfinished = jobs.submit(&client());
+        let now = Instant::now();
+        jobs.finish(&finished, result("200"), now);
+
+        assert_eq!(jobs.purge_expired(now + Duration::from_secs(30)), 0);
+        assert_eq!(jobs.purge_expired(now + Duration::from_secs(61)), 1);
+        assert!(jobs.status(&finished, &client()).is_none());
+        assert!(jobs.status(&unfinished, &client()).is_some());
+    }
+}
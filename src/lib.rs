@@ -2,5 +2,13 @@ pub mod error;
 pub mod helpers;
 pub mod protocol;
 pub mod broker;
+pub mod client;
 pub mod worker;
-pub mod pdf;
\ No newline at end of file
+pub mod pdf;
+pub mod envelope;
+pub mod jobs;
+pub mod pool;
+pub mod queue;
+pub mod quota;
+pub mod scaling;
+pub mod warning;
\ No newline at end of file
@@ -1,6 +1,6 @@
 use lazy_static::*;
 
-use super::error::{AnyError, Result};
+use super::error::{AnyError, ErrorKind, Result};
 use serde_json::Value;
 use std::collections::HashMap;
 
@@ -143,7 +143,7 @@ pub fn get_pdf_setting_value(pdf_setting: &PdfSetting, json_value: &Value) -> Re
         Some(v) => Ok(v),
         None => {
             let err_msg = build_err_msg(&pdf_setting, &json_value);
-            Err(AnyError::without_parent(err_msg.as_str()))
+            Err(AnyError::without_parent(ErrorKind::Validation, err_msg.as_str()))
         }
     }
 }
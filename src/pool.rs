@@ -0,0 +1,431 @@
+use super::envelope::ReplyTo;
+use super::protocol::WorkerCapabilities;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// A client request a worker is busy with, along with how many workers it has
+// been dispatched to so far.
+#[derive(Debug, Clone)]
+pub struct InFlight {
+    pub reply_to: ReplyTo,
+    pub request: Vec<u8>,
+    pub tenant: String,
+    pub attempts: u32,
+    pub since: Instant,
+}
+
+#[derive(Debug)]
+pub struct WorkerState {
+    pub capabilities: WorkerCapabilities,
+    pub expiry: Instant,
+    pub in_flight: Option<InFlight>,
+    pub idle_since: Instant,
+    pub renders: u32,
+    pub draining: bool,
+}
+
+// Workers known by the broker, keyed by their socket identity, along with the
+// ones ready to take a request. A worker that is not heard from before its
+// expiry is considered dead, wherever it runs.
+#[derive(Debug)]
+pub struct WorkerPool {
+    liveness: Duration,
+    workers: HashMap<String, WorkerState>,
+    available: VecDeque<String>,
+}
+
+impl WorkerPool {
+    pub fn new(liveness: Duration) -> WorkerPool {
+        let instance = WorkerPool {
+            liveness: liveness,
+            workers: HashMap::new(),
+            available: VecDeque::new(),
+        };
+        instance
+    }
+
+    // A worker telling it is ready again (e.g. after reconnecting) hands back the
+    // request it was busy with, if any, but is still the same process: its
+    // renders keep counting towards recycling, and a draining one stays so.
+    pub fn register(
+        &mut self,
+        worker_id: &str,
+        capabilities: WorkerCapabilities,
+    ) -> Option<InFlight> {
+        let expiry = Instant::now() + self.liveness;
+        let in_flight = match self.workers.get_mut(worker_id) {
+            Some(worker) => {
+                worker.capabilities = capabilities;
+                worker.expiry = expiry;
+                worker.in_flight.take()
+            }
+            None => {
+                self.workers.insert(
+                    worker_id.to_string(),
+                    WorkerState {
+                        capabilities: capabilities,
+                        expiry: expiry,
+                        in_flight: None,
+                        idle_since: Instant::now(),
+                        renders: 0,
+                        draining: false,
+                    },
+                );
+                None
+            }
+        };
+        self.release(worker_id);
+        in_flight
+    }
+
+    pub fn refresh(&mut self, worker_id: &str) -> bool {
+        match self.workers.get_mut(worker_id) {
+            Some(worker) => {
+                worker.expiry = Instant::now() + self.liveness;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn assign(
+        &mut self,
+        worker_id: &str,
+        reply_to: &ReplyTo,
+        request: &[u8],
+        tenant: &str,
+        attempts: u32,
+    ) {
+        if let Some(worker) = self.workers.get_mut(worker_id) {
+            worker.in_flight = Some(InFlight {
+                reply_to: reply_to.clone(),
+                request: request.to_vec(),
+                tenant: tenant.to_string(),
+                attempts: attempts,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    pub fn complete(&mut self, worker_id: &str) -> Option<InFlight> {
+        let worker = self.workers.get_mut(worker_id)?;
+        let in_flight = worker.in_flight.take();
+        if in_flight.is_some() {
+            worker.renders += 1;
+        }
+        in_flight
+    }
+
+    pub fn busy_with(&self, reply_to: &ReplyTo) -> Option<&str> {
+        self.workers
+            .iter()
+            .find(|(_, worker)| {
+                worker
+                    .in_flight
+                    .as_ref()
+                    .map_or(false, |in_flight| in_flight.reply_to == *reply_to)
+            })
+            .map(|(worker_id, _)| worker_id.as_str())
+    }
+
+    // Takes a request away from the worker busy with it, which is not given
+    // another one until it replies (to nobody) or tells it is ready again.
+    pub fn abort(&mut self, reply_to: &ReplyTo) -> Option<(String, InFlight)> {
+        let (worker_id, worker) = self.workers.iter_mut().find(|(_, worker)| {
+            worker
+                .in_flight
+                .as_ref()
+                .map_or(false, |in_flight| in_flight.reply_to == *reply_to)
+        })?;
+        let in_flight = worker.in_flight.take()?;
+        Some((worker_id.clone(), in_flight))
+    }
+
+    pub fn release(&mut self, worker_id: &str) {
+        if self.available.iter().any(|id| id == worker_id) {
+            return;
+        }
+        if let Some(worker) = self.workers.get_mut(worker_id) {
+            if worker.draining {
+                return;
+            }
+            worker.idle_since = Instant::now();
+            self.available.push_front(worker_id.to_string());
+        }
+    }
+
+    // Takes the worker out of the ready queue for good, so it can be stopped once
+    // done with its request. Tells whether it is idle already.
+    pub fn drain(&mut self, worker_id: &str) -> bool {
+        match self.workers.get_mut(worker_id) {
+            Some(worker) => {
+                worker.draining = true;
+                let idle = worker.in_flight.is_none();
+                self.available.retain(|id| id != worker_id);
+                idle
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_draining(&self, worker_id: &str) -> bool {
+        self.workers
+            .get(worker_id)
+            .map_or(false, |worker| worker.draining)
+    }
+
+    pub fn remove(&mut self, worker_id: &str) -> Option<WorkerState> {
+        self.available.retain(|id| id != worker_id);
+        self.workers.remove(worker_id)
+    }
+
+    pub fn next_available(&mut self) -> Option<String> {
+        self.available.pop_back()
+    }
+
+    pub fn has_available(&self) -> bool {
+        !self.available.is_empty()
+    }
+
+    pub fn available(&self) -> &VecDeque<String> {
+        &self.available
+    }
+
+    // Workers ready to take a request for longer than given, the longest idle first.
+    pub fn idle_workers(&self, now: Instant, idle_for: Duration) -> Vec<String> {
+        self.available
+            .iter()
+            .rev()
+            .filter(|worker_id| {
+                self.workers.get(*worker_id).map_or(false, |worker| {
+                    now.duration_since(worker.idle_since) >= idle_for
+                })
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, worker_id: &str) -> Option<&WorkerState> {
+        self.workers.get(worker_id)
+    }
+
+    pub fn worker_ids(&self) -> Vec<String> {
+        self.workers.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    pub fn purge_expired(&mut self, now: Instant) -> Vec<(String, WorkerState)> {
+        let expired: Vec<String> = self
+            .workers
+            .iter()
+            .filter(|(_, worker)| worker.expiry < now)
+            .map(|(worker_id, _)| worker_id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|worker_id| {
+                self.remove(&worker_id)
+                    .map(|worker| (worker_id.clone(), worker))
+            })
+            .collect()
+    }
+}
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::zmq_helpers::Identity;
+
+    fn client(client_id: &str) -> ReplyTo {
+        ReplyTo::client(&Identity::from(client_id))
+    }
+
+    fn capabilities() -> WorkerCapabilities {
+        WorkerCapabilities::new("localhost", 123)
+    }
+
+    #[test]
+    fn dispatch_in_ready_order() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        pool.register("W1", capabilities());
+        pool.register("W2", capabilities());
+        assert_eq!(pool.next_available().as_deref(), Some("W1"));
+        pool.release("W1");
+        assert_eq!(pool.next_available().as_deref(), Some("W2"));
+        assert_eq!(pool.next_available().as_deref(), Some("W1"));
+        assert!(!pool.has_available());
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn ignore_unknown_workers() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        pool.release("W1");
+        assert!(!pool.refresh("W1"));
+        assert!(!pool.has_available());
+    }
+
+    #[test]
+    fn purge_expired_workers() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        pool.register("W1", capabilities());
+        pool.register("W2", capabilities());
+        assert!(pool.purge_expired(Instant::now()).is_empty());
+
+        let expired = pool.purge_expired(Instant::now() + Duration::from_secs(4));
+        assert_eq!(expired.len(), 2);
+        assert!(pool.is_empty());
+        assert!(!pool.has_available());
+    }
+
+    #[test]
+    fn hand_in_flight_request_back_on_expiry() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        pool.register("W1", capabilities());
+        let worker_id = pool.next_available().unwrap();
+        pool.assign(&worker_id, &client("C1"), b"{}", "acme", 1);
+
+        let expired = pool.purge_expired(Instant::now() + Duration::from_secs(4));
+        let in_flight = expired[0].1.in_flight.as_ref().unwrap();
+        assert_eq!(expired[0].0, "W1");
+        assert_eq!(in_flight.reply_to, client("C1"));
+        assert_eq!(in_flight.request, b"{}".to_vec());
+        assert_eq!(in_flight.attempts, 1);
+    }
+
+    #[test]
+    fn complete_in_flight_request() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        pool.register("W1", capabilities());
+        pool.assign("W1", &client("C1"), b"{}", "acme", 1);
+        assert_eq!(pool.complete("W1").unwrap().reply_to, client("C1"));
+        assert!(pool.complete("W1").is_none());
+        assert!(pool.get("W1").unwrap().in_flight.is_none());
+    }
+
+    #[test]
+    fn abort_in_flight_request() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        pool.register("W1", capabilities());
+        pool.register("W2", capabilities());
+        let cancelled = ReplyTo::request(&Identity::from("C1"), Some("R1"));
+        assert_eq!(pool.next_available().unwrap(), "W1");
+        assert_eq!(pool.next_available().unwrap(), "W2");
+        pool.assign("W2", &client("C1"), b"{}", "acme", 1);
+        pool.assign("W1", &cancelled, b"{}", "acme", 1);
+
+        let (worker_id, in_flight) = pool.abort(&cancelled).unwrap();
+        assert_eq!(worker_id, "W1");
+        assert_eq!(in_flight.reply_to, cancelled);
+        assert!(pool.abort(&cancelled).is_none());
+
+        // the worker is left alone until it replies to nobody
+        assert!(!pool.has_available());
+        assert!(pool.complete("W1").is_none());
+        pool.release("W1");
+        assert!(!pool.is_draining("W1"));
+        assert_eq!(pool.available(), &VecDeque::from(vec![String::from("W1")]));
+        assert_eq!(pool.get("W1").unwrap().renders, 0);
+    }
+
+    #[test]
+    fn hand_in_flight_request_back_on_register() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        assert!(pool.register("W1", capabilities()).is_none());
+        let worker_id = pool.next_available().unwrap();
+        pool.assign(&worker_id, &client("C1"), b"{}", "acme", 1);
+
+        let in_flight = pool.register("W1", capabilities()).unwrap();
+        assert_eq!(in_flight.reply_to, client("C1"));
+        assert!(pool.get("W1").unwrap().in_flight.is_none());
+        assert!(pool.has_available());
+    }
+
+    #[test]
+    fn keep_state_on_register_again() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        pool.register("W1", capabilities());
+        let worker_id = pool.next_available().unwrap();
+        pool.assign(&worker_id, &client("C1"), b"{}", "acme", 1);
+        pool.complete(&worker_id);
+        pool.release(&worker_id);
+        pool.register("W1", capabilities());
+        assert_eq!(pool.get("W1").unwrap().renders, 1);
+
+        pool.drain("W1");
+        pool.register("W1", capabilities());
+        assert!(pool.is_draining("W1"));
+        assert!(!pool.has_available());
+    }
+
+    #[test]
+    fn remove_worker_from_ready_queue() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        pool.register("W1", capabilities());
+        pool.register("W2", capabilities());
+        assert!(pool.remove("W1").is_some());
+        assert_eq!(pool.next_available().as_deref(), Some("W2"));
+        assert_eq!(pool.next_available(), None);
+    }
+
+    #[test]
+    fn count_renders() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        pool.register("W1", capabilities());
+        for _ in 0..3 {
+            let worker_id = pool.next_available().unwrap();
+            pool.assign(&worker_id, &client("C1"), b"{}", "acme", 1);
+            pool.complete(&worker_id);
+            pool.release(&worker_id);
+        }
+        assert!(pool.complete("W1").is_none());
+        assert_eq!(pool.get("W1").unwrap().renders, 3);
+    }
+
+    #[test]
+    fn drain_worker() {
+        let mut pool = WorkerPool::new(Duration::from_secs(3));
+        pool.register("W1", capabilities());
+        pool.register("W2", capabilities());
+        let busy = pool.next_available().unwrap();
+        pool.assign(&busy, &client("C1"), b"{}", "acme", 1);
+
+        // idle worker leaves the ready queue right away
+        assert!(pool.drain("W2"));
+        assert!(!pool.has_available());
+
+        // busy one is not put back once done
+        assert!(!pool.drain("W1"));
+        pool.complete("W1");
+        pool.release("W1");
+        assert!(!pool.has_available());
+        assert!(pool.is_draining("W1"));
+    }
+
+    #[test]
+    fn list_idle_workers() {
+        let mut pool = WorkerPool::new(Duration::from_secs(60));
+        pool.register("W1", capabilities());
+        pool.register("W2", capabilities());
+        pool.register("W3", capabilities());
+        let busy = pool.next_available().unwrap();
+        assert_eq!(busy, "W1");
+
+        let now = Instant::now();
+        assert!(pool.idle_workers(now, Duration::from_secs(5)).is_empty());
+        assert_eq!(
+            pool.idle_workers(now + Duration::from_secs(6), Duration::from_secs(5)),
+            vec!["W2", "W3"]
+        );
+    }
+}
@@ -1,8 +1,844 @@
+use super::error::{error, error_without_parent, AnyError, ErrorKind, Result};
+use super::warning::WarningPolicy;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Component, Path};
+use std::time::Duration;
+
+pub const DEFAULT_FRONTEND_ENDPOINT: &str = "tcp://127.0.0.1:6660";
+pub const DEFAULT_BACKEND_ENDPOINT: &str = "tcp://127.0.0.1:6661";
 
 pub const MSG_WORKER_IS_READY: &str = "READY";
 pub const MSG_WORKER_IS_GONE: &str = "GONE";
+pub const MSG_HEARTBEAT: &str = "HEARTBEAT";
+pub const MSG_STOP_WORKER: &str = "STOP";
+// both what a client gives up on a render with and what broker tells the
+// worker rendering it
+pub const MSG_CANCEL: &str = "CANCEL";
+
+// a client with many renders in flight tells them apart by the ID it gives
+// each, and a client not willing to wait for its PDF submits a job, then asks
+// for its status and fetches its result later, by job ID; renders in flight
+// can be cancelled by their ID as well (with MSG_CANCEL), and jobs by theirs
+pub const CMD_RENDER: &str = "RENDER";
+pub const CMD_SUBMIT: &str = "SUBMIT";
+pub const CMD_STATUS: &str = "STATUS";
+pub const CMD_FETCH: &str = "FETCH";
+pub const CMD_CANCEL_JOB: &str = "CANCEL-JOB";
+
+// broker and workers heartbeat each other, and a peer is given up on after
+// missing that many heartbeats in a row (these are the defaults)
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+pub const HEARTBEAT_LIVENESS: u32 = 3;
+
+// no heartbeat at all would make both event loops spin, and one too far apart
+// would let a dead peer go unnoticed for too long
+pub const MAX_HEARTBEAT_INTERVAL_MS: u64 = 60 * 1000;
+pub const MAX_HEARTBEAT_LIVENESS: u32 = 100;
+
+pub fn heartbeat_interval(interval_ms: u64) -> Result<Duration> {
+    if interval_ms == 0 || interval_ms > MAX_HEARTBEAT_INTERVAL_MS {
+        return error_without_parent(
+            ErrorKind::Config,
+            format!(
+                "Heartbeat must be 1 to {} milliseconds",
+                MAX_HEARTBEAT_INTERVAL_MS
+            )
+            .as_str(),
+        );
+    }
+    Ok(Duration::from_millis(interval_ms))
+}
+
+pub fn heartbeat_liveness(liveness: u32) -> Result<u32> {
+    if liveness == 0 || liveness > MAX_HEARTBEAT_LIVENESS {
+        return error_without_parent(
+            ErrorKind::Config,
+            format!(
+                "Liveness must be 1 to {} heartbeats",
+                MAX_HEARTBEAT_LIVENESS
+            )
+            .as_str(),
+        );
+    }
+    Ok(liveness)
+}
 
 pub const REP_200_SUCCESS: &str = "200";
+pub const REP_202_ACCEPTED: &str = "202";
 pub const REP_400_BAD_REQUEST: &str = "400";
+pub const REP_404_NOT_FOUND: &str = "404";
+pub const REP_409_CONFLICT: &str = "409";
+pub const REP_422_UNPROCESSABLE: &str = "422";
+pub const REP_429_TOO_MANY_REQUESTS: &str = "429";
+// as nginx does when its client closed the request
+pub const REP_499_CANCELLED: &str = "499";
+pub const REP_500_INTERNAL_ERROR: &str = "500";
 pub const REP_502_BAD_GATEWAY: &str = "502";
 pub const REP_503_SERVICE_UNAVAILABLE: &str = "503";
+pub const REP_504_GATEWAY_TIMEOUT: &str = "504";
+
+// Reply code a client gets for a request failing with a given kind of error.
+pub fn reply_code(kind: ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::Validation | ErrorKind::Protocol => REP_400_BAD_REQUEST,
+        ErrorKind::Render => REP_422_UNPROCESSABLE,
+        ErrorKind::Config | ErrorKind::Io => REP_500_INTERNAL_ERROR,
+        ErrorKind::Transport => REP_502_BAD_GATEWAY,
+        ErrorKind::Timeout => REP_504_GATEWAY_TIMEOUT,
+    }
+}
+
+// Wire contract
+//
+// A client sends a JSON encoded RenderRequest as REQUEST and gets back a JSON
+// encoded RenderReply as CONTENT, optionally followed by the PDF binary.
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// a request may not hold a worker for longer than that
+pub const MAX_TIMEOUT_MS: u64 = 10 * 60 * 1000;
+
+fn default_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    File,
+    Content,
+    Both,
+}
+
+impl OutputMode {
+    pub fn saves_file(&self) -> bool {
+        *self != OutputMode::Content
+    }
+
+    pub fn returns_content(&self) -> bool {
+        *self != OutputMode::File
+    }
+}
+
+impl Default for OutputMode {
+    fn default() -> Self {
+        OutputMode::File
+    }
+}
+
+// Requests waiting for a worker are served higher priority first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+// What a request wants done when rendering raises a warning it cares about:
+// fail (abort), carry on (ignore) or carry on and reply them along with the PDF
+// (collect).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WarningAction {
+    Abort,
+    Ignore,
+    Collect,
+}
+
+impl Default for WarningAction {
+    fn default() -> Self {
+        WarningAction::Abort
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct OnWarning {
+    #[serde(default)]
+    pub action: WarningAction,
+    #[serde(default)]
+    pub trigger_words: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trigger_patterns: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PageSource {
+    Url(String),
+    Html(String),
+}
+
+// A page of the document to be rendered, i.e. where it comes from plus the
+// object settings that apply to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageObject {
+    pub source: PageSource,
+    pub settings: Map<String, Value>,
+}
+
+// Either `url` or `html` for a single page document or `objects` for a multi-page
+// one, where each entry carries its own `page` or `html` and overrides the `object`
+// settings. Inline HTML might refer to `assets` by their relative names, which
+// are sent base64 encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RenderRequest {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub assets: BTreeMap<String, String>,
+    #[serde(default)]
+    pub global: Map<String, Value>,
+    #[serde(default)]
+    pub object: Map<String, Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub objects: Vec<Map<String, Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_warning: Option<OnWarning>,
+    #[serde(default)]
+    pub output: OutputMode,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+impl RenderRequest {
+    pub fn new(url: &str) -> RenderRequest {
+        RenderRequest {
+            version: PROTOCOL_VERSION,
+            url: Some(url.to_string()),
+            html: None,
+            assets: BTreeMap::new(),
+            global: Map::new(),
+            object: Map::new(),
+            objects: Vec::new(),
+            on_warning: None,
+            output: OutputMode::default(),
+            priority: Priority::default(),
+            tenant: None,
+            timeout_ms: None,
+        }
+    }
+
+    pub fn with_pages(pages: &[&str]) -> RenderRequest {
+        let mut request = RenderRequest {
+            url: None,
+            ..RenderRequest::new("")
+        };
+        for page in pages {
+            request.add_object(page, Map::new());
+        }
+        request
+    }
+
+    pub fn with_html(html: &str) -> RenderRequest {
+        RenderRequest {
+            url: None,
+            html: Some(html.to_string()),
+            ..RenderRequest::new("")
+        }
+    }
+
+    pub fn add_object(&mut self, page: &str, settings: Map<String, Value>) {
+        let mut object = settings;
+        object.insert(String::from("page"), Value::String(page.to_string()));
+        self.objects.push(object);
+    }
+
+    pub fn add_html_object(&mut self, html: &str, settings: Map<String, Value>) {
+        let mut object = settings;
+        object.insert(String::from("html"), Value::String(html.to_string()));
+        self.objects.push(object);
+    }
+
+    pub fn add_asset(&mut self, name: &str, content: &[u8]) {
+        self.assets
+            .insert(name.to_string(), base64::encode(content));
+    }
+
+    pub fn has_inline_content(&self) -> bool {
+        self.html.is_some()
+            || !self.assets.is_empty()
+            || self
+                .objects
+                .iter()
+                .any(|object| object.contains_key("html"))
+    }
+
+    // How long rendering may take, unless the request tells it.
+    pub fn timeout(&self, default: Duration) -> Duration {
+        self.timeout_ms.map_or(default, Duration::from_millis)
+    }
+
+    pub fn page_objects(&self) -> Result<Vec<PageObject>> {
+        // the page comes from url, html or objects, never from the shared settings
+        let mut shared_settings = self.object.clone();
+        shared_settings.remove("page");
+
+        let single_source = match (&self.url, &self.html) {
+            (Some(_), Some(_)) => {
+                return error_without_parent(
+                    ErrorKind::Validation,
+                    "Request must have either url or html, not both",
+                )
+            }
+            (Some(url), None) => Some(PageSource::Url(url.clone())),
+            (None, Some(html)) => Some(PageSource::Html(html.clone())),
+            (None, None) => None,
+        };
+
+        if let Some(source) = single_source {
+            if !self.objects.is_empty() {
+                return error_without_parent(
+                    ErrorKind::Validation,
+                    "Request must have either url/html or objects, not both",
+                );
+            }
+            return Ok(vec![PageObject {
+                source: source,
+                settings: shared_settings,
+            }]);
+        }
+
+        if self.objects.is_empty() {
+            return error_without_parent(
+                ErrorKind::Validation,
+                "Request must have one of url, html or objects",
+            );
+        }
+
+        let mut page_objects = Vec::new();
+        for (i, object) in self.objects.iter().enumerate() {
+            let source = match (object.get("page"), object.get("html")) {
+                (Some(Value::String(page)), None) => PageSource::Url(page.clone()),
+                (None, Some(Value::String(html))) => PageSource::Html(html.clone()),
+                _ => {
+                    return error_without_parent(
+                        ErrorKind::Validation,
+                        format!("Object #{} must have either a page or an html string", i).as_str(),
+                    )
+                }
+            };
+            let mut settings = shared_settings.clone();
+            for (key, value) in object {
+                if key != "page" && key != "html" {
+                    settings.insert(key.clone(), value.clone());
+                }
+            }
+            page_objects.push(PageObject {
+                source: source,
+                settings: settings,
+            });
+        }
+        Ok(page_objects)
+    }
+
+    pub fn from_json(payload: &str) -> Result<RenderRequest> {
+        let request: RenderRequest = match serde_json::from_str(payload) {
+            Ok(parsed) => parsed,
+            Err(reason) => return error(ErrorKind::Validation, "Invalid request", reason),
+        };
+        if request.version != PROTOCOL_VERSION {
+            return error_without_parent(
+                ErrorKind::Protocol,
+                format!(
+                    "Unsupported protocol version {}, expected {}",
+                    request.version, PROTOCOL_VERSION
+                )
+                .as_str(),
+            );
+        }
+        match request.timeout_ms {
+            Some(0) => {
+                return error_without_parent(
+                    ErrorKind::Validation,
+                    "Request timeoutMs must be greater than 0",
+                )
+            }
+            Some(timeout_ms) if timeout_ms > MAX_TIMEOUT_MS => {
+                return error_without_parent(
+                    ErrorKind::Validation,
+                    format!("Request timeoutMs must be at most {}", MAX_TIMEOUT_MS).as_str(),
+                )
+            }
+            _ => {}
+        }
+        request.page_objects()?;
+        for name in request.assets.keys() {
+            validate_asset_name(name)?;
+        }
+        WarningPolicy::new(request.on_warning.as_ref())?;
+        Ok(request)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("failed serializing request")
+    }
+}
+
+// The few request properties the broker schedules by. Validating the request
+// is up to the worker, so anything unreadable here just gets the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RequestHeader {
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+impl RequestHeader {
+    pub fn peek(payload: &[u8]) -> RequestHeader {
+        serde_json::from_slice(payload).unwrap_or_default()
+    }
+
+    // Requests that do not tell their tenant share an anonymous one.
+    pub fn tenant(&self) -> &str {
+        self.tenant.as_deref().unwrap_or("")
+    }
+}
+
+// Assets are materialised next to the inline HTML, so their names must stay
+// inside that directory.
+pub fn validate_asset_name(name: &str) -> Result<()> {
+    let path = Path::new(name);
+    let is_relative = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if name.is_empty() || !is_relative {
+        return error_without_parent(
+            ErrorKind::Validation,
+            format!("Asset name must be a plain relative path: {}", name).as_str(),
+        );
+    }
+    Ok(())
+}
+
+// What a worker announces about itself along with READY, so brokers can tell
+// apart workers they spawned from those joining over the network.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkerCapabilities {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    pub host: String,
+    pub pid: u32,
+}
+
+impl WorkerCapabilities {
+    pub fn new(host: &str, pid: u32) -> WorkerCapabilities {
+        WorkerCapabilities {
+            version: PROTOCOL_VERSION,
+            host: host.to_string(),
+            pid: pid,
+        }
+    }
+
+    pub fn from_json(payload: &[u8]) -> Result<WorkerCapabilities> {
+        match serde_json::from_slice(payload) {
+            Ok(parsed) => Ok(parsed),
+            Err(reason) => error(ErrorKind::Protocol, "Invalid worker capabilities", reason),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("failed serializing worker capabilities")
+    }
+}
+
+// Where a render failed, so clients can tell a bad request (parse) from pages
+// that could not be loaded or converted, or a PDF that could not be written.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderStage {
+    Parse,
+    Load,
+    Convert,
+    Write,
+}
+
+impl fmt::Display for RenderStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderStage::Parse => write!(f, "parse"),
+            RenderStage::Load => write!(f, "load"),
+            RenderStage::Convert => write!(f, "convert"),
+            RenderStage::Write => write!(f, "write"),
+        }
+    }
+}
+
+// Everything wkhtmltopdf reported while converting, so it can be told why a
+// page rendered blank without going through worker's output: every warning,
+// the error conversion failed with if any, and how long it took. Phases and
+// progress are missing, the wkhtmltopdf bindings have no callbacks for them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostics {
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    #[serde(default)]
+    pub errors: Vec<String>,
+    #[serde(default)]
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Rendering,
+    Done,
+    Failed,
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobState::Queued => write!(f, "queued"),
+            JobState::Rendering => write!(f, "rendering"),
+            JobState::Done => write!(f, "done"),
+            JobState::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+// Where a submitted job stands. There is no progress of a render in it, for
+// the same reason Diagnostics have none.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatus {
+    pub id: String,
+    pub state: JobState,
+}
+
+// Unknown fields are tolerated on replies, so older clients can talk to newer
+// workers as long as the version is the same.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenderReply {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    pub code: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<RenderStage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ErrorKind>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<Diagnostics>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<JobStatus>,
+}
+
+impl RenderReply {
+    pub fn success(message: &str, path: Option<String>, size: usize) -> RenderReply {
+        RenderReply {
+            version: PROTOCOL_VERSION,
+            code: String::from(REP_200_SUCCESS),
+            message: message.to_string(),
+            path: path,
+            size: Some(size),
+            stage: None,
+            kind: None,
+            warnings: Vec::new(),
+            diagnostics: None,
+            job: None,
+        }
+    }
+
+    pub fn error(code: &str, message: &str) -> RenderReply {
+        RenderReply {
+            version: PROTOCOL_VERSION,
+            code: code.to_string(),
+            message: message.to_string(),
+            path: None,
+            size: None,
+            stage: None,
+            kind: None,
+            warnings: Vec::new(),
+            diagnostics: None,
+            job: None,
+        }
+    }
+
+    pub fn failure(reason: &AnyError, stage: RenderStage) -> RenderReply {
+        RenderReply {
+            stage: Some(stage),
+            kind: Some(reason.kind),
+            ..RenderReply::error(reply_code(reason.kind), reason.to_string().as_str())
+        }
+    }
+
+    pub fn job(code: &str, message: &str, status: JobStatus) -> RenderReply {
+        RenderReply {
+            job: Some(status),
+            ..RenderReply::error(code, message)
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.code == REP_200_SUCCESS
+    }
+
+    pub fn from_json(payload: &[u8]) -> Result<RenderReply> {
+        match serde_json::from_slice(payload) {
+            Ok(parsed) => Ok(parsed),
+            Err(reason) => error(ErrorKind::Protocol, "Invalid reply", reason),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("failed serializing reply")
+    }
+}
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_minimal_request() {
+        let request = RenderRequest::from_json(r#"{"url": "file:///tmp/a.html"}"#).unwrap();
+        assert_eq!(request.version, PROTOCOL_VERSION);
+        assert_eq!(request.url.as_deref(), Some("file:///tmp/a.html"));
+        assert_eq!(request.output, OutputMode::File);
+        assert!(request.global.is_empty());
+        assert!(request.on_warning.is_none());
+    }
+
+    #[test]
+    fn parse_full_request() {
+        let request = RenderRequest::from_json(
+            r#"{
+                "version": 1,
+                "url": "file:///tmp/a.html",
+                "global": {"copies": 2},
+                "object": {"load.windowStatus": "ready"},
+                "onWarning": {"action": "abort", "triggerWords": ["error"]},
+                "output": "both",
+                "priority": "high"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(request.global["copies"], 2);
+        assert_eq!(request.object["load.windowStatus"], "ready");
+        let on_warning = request.on_warning.unwrap();
+        assert_eq!(on_warning.action, WarningAction::Abort);
+        assert_eq!(on_warning.trigger_words, vec!["error"]);
+        assert!(request.output.saves_file());
+        assert!(request.output.returns_content());
+        assert_eq!(request.priority, Priority::High);
+    }
+
+    #[test]
+    fn parse_multi_page_request() {
+        let request = RenderRequest::from_json(
+            r#"{
+                "object": {"load.windowStatus": "ready", "useLocalLinks": true},
+                "objects": [
+                    {"page": "file:///tmp/cover.html", "load.windowStatus": "cover"},
+                    {"page": "file:///tmp/body.html"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let pages = request.page_objects().unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(
+            pages[0].source,
+            PageSource::Url(String::from("file:///tmp/cover.html"))
+        );
+        assert_eq!(pages[0].settings["load.windowStatus"], "cover");
+        assert_eq!(pages[0].settings["useLocalLinks"], true);
+        assert!(!pages[0].settings.contains_key("page"));
+        assert_eq!(
+            pages[1].source,
+            PageSource::Url(String::from("file:///tmp/body.html"))
+        );
+        assert_eq!(pages[1].settings["load.windowStatus"], "ready");
+    }
+
+    #[test]
+    fn parse_inline_html_request() {
+        let request = RenderRequest::from_json(
+            r#"{
+                "objects": [
+                    {"html": "<h1>Cover</h1>"},
+                    {"page": "file:///tmp/body.html"}
+                ],
+                "assets": {"css/site.css": "aDEgeyB9"}
+            }"#,
+        )
+        .unwrap();
+        assert!(request.has_inline_content());
+        let pages = request.page_objects().unwrap();
+        assert_eq!(
+            pages[0].source,
+            PageSource::Html(String::from("<h1>Cover</h1>"))
+        );
+        assert!(!pages[0].settings.contains_key("html"));
+
+        let request = RenderRequest::from_json(r#"{"html": "<p>Hi</p>"}"#).unwrap();
+        assert!(request.has_inline_content());
+        assert!(!RenderRequest::new("file:///tmp/a.html").has_inline_content());
+    }
+
+    #[test]
+    fn reject_unsafe_asset_names() {
+        assert!(validate_asset_name("logo.png").is_ok());
+        assert!(validate_asset_name("css/site.css").is_ok());
+        assert!(validate_asset_name("").is_err());
+        assert!(validate_asset_name("../etc/passwd").is_err());
+        assert!(validate_asset_name("/etc/passwd").is_err());
+        assert!(validate_asset_name("css/../../x").is_err());
+        assert!(RenderRequest::from_json(r#"{"html": "x", "assets": {"../x": ""}}"#).is_err());
+    }
+
+    #[test]
+    fn reject_requests_without_pages() {
+        assert!(RenderRequest::from_json(r#"{}"#).is_err());
+        assert!(RenderRequest::from_json(r#"{"objects": [{"useLocalLinks": true}]}"#).is_err());
+        assert!(RenderRequest::from_json(
+            r#"{"url": "file:///tmp/a.html", "objects": [{"page": "file:///tmp/b.html"}]}"#
+        )
+        .is_err());
+        assert!(RenderRequest::from_json(r#"{"url": "file:///tmp/a.html", "html": "x"}"#).is_err());
+        assert!(RenderRequest::from_json(r#"{"objects": [{"page": "a", "html": "x"}]}"#).is_err());
+    }
+
+    #[test]
+    fn parse_warning_policy() {
+        let request = RenderRequest::from_json(
+            r#"{
+                "url": "file:///tmp/a.html",
+                "onWarning": {"action": "collect", "triggerPatterns": ["^Failed to load"]}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(request.on_warning.unwrap().action, WarningAction::Collect);
+        assert!(RenderRequest::from_json(
+            r#"{"url": "file:///tmp/a.html", "onWarning": {"action": "panic"}}"#
+        )
+        .is_err());
+        assert!(RenderRequest::from_json(
+            r#"{"url": "file:///tmp/a.html", "onWarning": {"triggerPatterns": ["("]}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn reject_unknown_fields() {
+        let result = RenderRequest::from_json(r#"{"url": "file:///tmp/a.html", "ulr": 1}"#);
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("unknown field `ulr`"));
+    }
+
+    #[test]
+    fn reject_unsupported_version() {
+        let result = RenderRequest::from_json(r#"{"version": 99, "url": "file:///tmp/a.html"}"#);
+        let err = result.unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Protocol);
+        assert!(err.details.contains("Unsupported protocol version 99"));
+    }
+
+    #[test]
+    fn parse_request_timeout() {
+        let request =
+            RenderRequest::from_json(r#"{"url": "file:///tmp/a.html", "timeoutMs": 60000}"#)
+                .unwrap();
+        assert_eq!(
+            request.timeout(Duration::from_secs(5)),
+            Duration::from_secs(60)
+        );
+        let request = RenderRequest::new("file:///tmp/a.html");
+        assert_eq!(
+            request.timeout(Duration::from_secs(5)),
+            Duration::from_secs(5)
+        );
+        assert!(
+            RenderRequest::from_json(r#"{"url": "file:///tmp/a.html", "timeoutMs": 0}"#).is_err()
+        );
+        let reason = RenderRequest::from_json(
+            r#"{"url": "file:///tmp/a.html", "timeoutMs": 18446744073709551615}"#,
+        )
+        .unwrap_err();
+        assert_eq!(reason.kind, ErrorKind::Validation);
+    }
+
+    #[test]
+    fn bound_heartbeats() {
+        assert_eq!(heartbeat_interval(1000).unwrap(), Duration::from_secs(1));
+        assert_eq!(heartbeat_interval(0).unwrap_err().kind, ErrorKind::Config);
+        assert!(heartbeat_interval(u64::MAX).is_err());
+        assert_eq!(heartbeat_liveness(3).unwrap(), 3);
+        assert!(heartbeat_liveness(0).is_err());
+        assert!(heartbeat_liveness(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn peek_request_header() {
+        let header = RequestHeader::peek(
+            br#"{"url": "file:///tmp/a.html", "priority": "low", "tenant": "acme"}"#,
+        );
+        assert_eq!(header.priority, Priority::Low);
+        assert_eq!(header.tenant(), "acme");
+        assert_eq!(RequestHeader::peek(b"garbage").priority, Priority::Normal);
+        assert_eq!(RequestHeader::peek(b"garbage").tenant(), "");
+        assert!(Priority::High > Priority::Normal && Priority::Normal > Priority::Low);
+    }
+
+    #[test]
+    fn roundtrip_reply() {
+        let reply = RenderReply::success("PDF built", Some(String::from("out/a.pdf")), 42);
+        let parsed = RenderReply::from_json(reply.to_json().as_bytes()).unwrap();
+        assert_eq!(parsed, reply);
+        assert!(parsed.is_success());
+        assert!(!RenderReply::error(REP_400_BAD_REQUEST, "bad").is_success());
+
+        let reason = AnyError::without_parent(ErrorKind::Render, "no host");
+        let reply = RenderReply::failure(&reason, RenderStage::Convert);
+        assert_eq!(reply.code, REP_422_UNPROCESSABLE);
+        let json = reply.to_json();
+        assert!(json.contains(r#""stage":"convert""#));
+        assert!(json.contains(r#""kind":"render""#));
+        assert_eq!(RenderReply::from_json(json.as_bytes()).unwrap(), reply);
+
+        let mut reply = RenderReply::success("PDF built", None, 42);
+        reply.diagnostics = Some(Diagnostics {
+            errors: vec![String::from(
+                "Failed to load http://nowhere/, with network status code 3",
+            )],
+            elapsed_ms: 120,
+            ..Diagnostics::default()
+        });
+        let json = reply.to_json();
+        assert!(json.contains(r#""elapsedMs":120"#));
+        assert_eq!(RenderReply::from_json(json.as_bytes()).unwrap(), reply);
+    }
+}
@@ -0,0 +1,300 @@
+use super::envelope::ReplyTo;
+use super::helpers::zmq_helpers::Identity;
+use super::protocol::Priority;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// A client request waiting for a worker to become available.
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub reply_to: ReplyTo,
+    pub request: Vec<u8>,
+    pub tenant: String,
+    pub queued_at: Instant,
+    pub deadline: Instant,
+}
+
+// Requests of a single priority, served one client at a time in turns, so a
+// client sending many requests cannot starve the others.
+#[derive(Debug, Default)]
+struct FairQueue {
+    turns: VecDeque<Identity>,
+    pending: HashMap<Identity, VecDeque<Pending>>,
+}
+
+impl FairQueue {
+    fn push(&mut self, request: Pending) {
+        let client_id = request.reply_to.client_id.clone();
+        let pending = self.pending.entry(client_id.clone()).or_default();
+        if pending.is_empty() {
+            self.turns.push_back(client_id);
+        }
+        pending.push_back(request);
+    }
+
+    fn pop(&mut self) -> Option<Pending> {
+        let client_id = self.turns.pop_front()?;
+        let pending = self.pending.get_mut(&client_id)?;
+        let request = pending.pop_front();
+        if pending.is_empty() {
+            self.pending.remove(&client_id);
+        } else {
+            self.turns.push_back(client_id);
+        }
+        request
+    }
+
+    fn remove(&mut self, reply_to: &ReplyTo) -> Option<Pending> {
+        let pending = self.pending.get_mut(&reply_to.client_id)?;
+        let position = pending
+            .iter()
+            .position(|request| request.reply_to == *reply_to)?;
+        let request = pending.remove(position);
+        if pending.is_empty() {
+            self.pending.remove(&reply_to.client_id);
+            self.turns
+                .retain(|client_id| *client_id != reply_to.client_id);
+        }
+        request
+    }
+
+    fn purge_expired(&mut self, now: Instant) -> Vec<Pending> {
+        let mut expired = Vec::new();
+        for client_id in self.turns.iter() {
+            if let Some(pending) = self.pending.get_mut(client_id) {
+                while pending
+                    .front()
+                    .map_or(false, |request| request.deadline <= now)
+                {
+                    expired.push(pending.pop_front().unwrap());
+                }
+            }
+        }
+        let pending = &mut self.pending;
+        pending.retain(|_, requests| !requests.is_empty());
+        self.turns
+            .retain(|client_id| pending.contains_key(client_id));
+        expired
+    }
+
+    fn oldest(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .filter_map(|requests| requests.front())
+            .map(|request| request.queued_at)
+            .min()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.turns.is_empty()
+    }
+}
+
+// Client requests waiting for a worker, higher priority first and fair across
+// clients of the same priority. It is bounded in depth and in how long a
+// request may wait, so clients are told early the cluster is overloaded rather
+// than piling up in socket buffers.
+#[derive(Debug)]
+pub struct RequestQueue {
+    max_depth: usize,
+    max_wait: Duration,
+    len: usize,
+    levels: BTreeMap<Priority, FairQueue>,
+}
+
+impl RequestQueue {
+    pub fn new(max_depth: usize, max_wait: Duration) -> RequestQueue {
+        let instance = RequestQueue {
+            max_depth: max_depth,
+            max_wait: max_wait,
+            len: 0,
+            levels: BTreeMap::new(),
+        };
+        instance
+    }
+
+    pub fn push(
+        &mut self,
+        reply_to: &ReplyTo,
+        request: &[u8],
+        tenant: &str,
+        priority: Priority,
+    ) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        let now = Instant::now();
+        self.levels.entry(priority).or_default().push(Pending {
+            reply_to: reply_to.clone(),
+            request: request.to_vec(),
+            tenant: tenant.to_string(),
+            queued_at: now,
+            deadline: now + self.max_wait,
+        });
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<Pending> {
+        let request = self
+            .levels
+            .values_mut()
+            .rev()
+            .find(|level| !level.is_empty())
+            .and_then(|level| level.pop());
+        if request.is_some() {
+            self.len -= 1;
+        }
+        request
+    }
+
+    // Takes a request out of the queue wherever it is, e.g. once cancelled.
+    pub fn remove(&mut self, reply_to: &ReplyTo) -> Option<Pending> {
+        let request = self
+            .levels
+            .values_mut()
+            .find_map(|level| level.remove(reply_to));
+        if request.is_some() {
+            self.len -= 1;
+        }
+        request
+    }
+
+    pub fn purge_expired(&mut self, now: Instant) -> Vec<Pending> {
+        let mut expired = Vec::new();
+        for level in self.levels.values_mut().rev() {
+            expired.extend(level.purge_expired(now));
+        }
+        self.len -= expired.len();
+        expired
+    }
+
+    pub fn longest_wait(&self, now: Instant) -> Duration {
+        self.levels
+            .values()
+            .filter_map(|level| level.oldest())
+            .min()
+            .map_or(Duration::from_secs(0), |oldest| now.duration_since(oldest))
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len >= self.max_depth
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(client_id: &str) -> ReplyTo {
+        ReplyTo::client(&Identity::from(client_id))
+    }
+
+    fn next_request(queue: &mut RequestQueue) -> String {
+        let pending = queue.pop().unwrap();
+        format!(
+            "{}:{}",
+            pending.reply_to.client_id,
+            String::from_utf8(pending.request).unwrap()
+        )
+    }
+
+    #[test]
+    fn pop_in_arrival_order() {
+        let mut queue = RequestQueue::new(10, Duration::from_secs(3));
+        assert!(queue.push(&client("C1"), b"1", "acme", Priority::Normal));
+        assert!(queue.push(&client("C2"), b"2", "acme", Priority::Normal));
+        assert_eq!(queue.pop().unwrap().reply_to, client("C1"));
+        assert_eq!(queue.pop().unwrap().reply_to, client("C2"));
+        assert!(queue.pop().is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn pop_higher_priority_first() {
+        let mut queue = RequestQueue::new(10, Duration::from_secs(3));
+        queue.push(&client("C1"), b"batch", "acme", Priority::Low);
+        queue.push(&client("C2"), b"report", "acme", Priority::Normal);
+        queue.push(&client("C3"), b"invoice", "acme", Priority::High);
+        assert_eq!(next_request(&mut queue), "C3:invoice");
+        assert_eq!(next_request(&mut queue), "C2:report");
+        assert_eq!(next_request(&mut queue), "C1:batch");
+    }
+
+    #[test]
+    fn take_turns_between_clients() {
+        let mut queue = RequestQueue::new(10, Duration::from_secs(3));
+        queue.push(&client("C1"), b"1", "acme", Priority::Normal);
+        queue.push(&client("C1"), b"2", "acme", Priority::Normal);
+        queue.push(&client("C1"), b"3", "acme", Priority::Normal);
+        queue.push(&client("C2"), b"1", "acme", Priority::Normal);
+        queue.push(&client("C3"), b"1", "acme", Priority::Normal);
+        assert_eq!(next_request(&mut queue), "C1:1");
+        assert_eq!(next_request(&mut queue), "C2:1");
+        assert_eq!(next_request(&mut queue), "C3:1");
+        assert_eq!(next_request(&mut queue), "C1:2");
+        queue.push(&client("C2"), b"2", "acme", Priority::Normal);
+        assert_eq!(next_request(&mut queue), "C1:3");
+        assert_eq!(next_request(&mut queue), "C2:2");
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn refuse_requests_when_full() {
+        let mut queue = RequestQueue::new(2, Duration::from_secs(3));
+        assert!(queue.push(&client("C1"), b"1", "acme", Priority::Normal));
+        assert!(queue.push(&client("C2"), b"2", "acme", Priority::High));
+        assert!(queue.is_full());
+        assert!(!queue.push(&client("C3"), b"3", "acme", Priority::High));
+        assert_eq!(queue.len(), 2);
+
+        queue.pop();
+        assert!(queue.push(&client("C3"), b"3", "acme", Priority::Low));
+    }
+
+    #[test]
+    fn remove_cancelled_requests() {
+        let mut queue = RequestQueue::new(10, Duration::from_secs(3));
+        let first = ReplyTo::request(&Identity::from("C1"), Some("R1"));
+        let second = ReplyTo::request(&Identity::from("C1"), Some("R2"));
+        queue.push(&first, b"1", "acme", Priority::Normal);
+        queue.push(&second, b"2", "acme", Priority::High);
+        queue.push(&client("C2"), b"1", "acme", Priority::Normal);
+
+        assert_eq!(queue.remove(&second).unwrap().request, b"2".to_vec());
+        assert!(queue.remove(&second).is_none());
+        assert_eq!(queue.remove(&first).unwrap().request, b"1".to_vec());
+        assert_eq!(queue.len(), 1);
+        assert_eq!(next_request(&mut queue), "C2:1");
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn purge_expired_requests() {
+        let mut queue = RequestQueue::new(10, Duration::from_secs(3));
+        queue.push(&client("C1"), b"1", "acme", Priority::Normal);
+        queue.push(&client("C2"), b"2", "acme", Priority::Low);
+        assert!(queue.purge_expired(Instant::now()).is_empty());
+        assert!(
+            queue.longest_wait(Instant::now() + Duration::from_secs(2)) >= Duration::from_secs(2)
+        );
+
+        let expired = queue.purge_expired(Instant::now() + Duration::from_secs(4));
+        assert_eq!(expired.len(), 2);
+        assert_eq!(expired[0].reply_to, client("C1"));
+        assert!(queue.is_empty());
+        assert!(queue.pop().is_none());
+        assert_eq!(queue.longest_wait(Instant::now()), Duration::from_secs(0));
+    }
+}
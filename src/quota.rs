@@ -0,0 +1,230 @@
+use super::error::{error_without_parent, ErrorKind, Result};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// Limits of a single tenant, where None means unlimited. Concurrency counts
+// requests accepted and not yet replied to, whether queued or rendering.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Quota {
+    pub max_concurrency: Option<usize>,
+    pub max_per_minute: Option<usize>,
+}
+
+impl Quota {
+    pub fn new(max_concurrency: usize, max_per_minute: usize) -> Quota {
+        // zero is how the command line says unlimited
+        let limit = |value: usize| if value == 0 { None } else { Some(value) };
+        Quota {
+            max_concurrency: limit(max_concurrency),
+            max_per_minute: limit(max_per_minute),
+        }
+    }
+
+    // Parses a `TENANT=CONCURRENCY/RPM` quota override.
+    pub fn parse_override(spec: &str) -> Result<(String, Quota)> {
+        let invalid = || {
+            error_without_parent(
+                ErrorKind::Config,
+                format!("Quota must look like TENANT=CONCURRENCY/RPM: {}", spec).as_str(),
+            )
+        };
+        let mut parts = spec.splitn(2, '=');
+        let tenant = parts.next().unwrap_or_default();
+        let limits: Vec<&str> = parts.next().unwrap_or_default().split('/').collect();
+        if tenant.is_empty() || limits.len() != 2 {
+            return invalid();
+        }
+        match (limits[0].parse::<usize>(), limits[1].parse::<usize>()) {
+            (Ok(max_concurrency), Ok(max_per_minute)) => Ok((
+                tenant.to_string(),
+                Quota::new(max_concurrency, max_per_minute),
+            )),
+            _ => invalid(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TenantUsage {
+    active: usize,
+    admitted: VecDeque<Instant>,
+}
+
+// Usage of every tenant against its quota. Tenants are whatever requests
+// claim to be on behalf of, so only ones given their own quota are told apart;
+// any other tenant, made up or not, draws from one shared default bucket.
+#[derive(Debug)]
+pub struct TenantQuotas {
+    default: Quota,
+    overrides: HashMap<String, Quota>,
+    usage: HashMap<String, TenantUsage>,
+    shared: TenantUsage,
+}
+
+impl TenantQuotas {
+    pub fn new(default: Quota, overrides: HashMap<String, Quota>) -> TenantQuotas {
+        let instance = TenantQuotas {
+            default: default,
+            overrides: overrides,
+            usage: HashMap::new(),
+            shared: TenantUsage::default(),
+        };
+        instance
+    }
+
+    fn usage_mut(&mut self, tenant: &str) -> &mut TenantUsage {
+        if self.overrides.contains_key(tenant) {
+            self.usage.entry(tenant.to_string()).or_default()
+        } else {
+            &mut self.shared
+        }
+    }
+
+    pub fn quota(&self, tenant: &str) -> Quota {
+        *self.overrides.get(tenant).unwrap_or(&self.default)
+    }
+
+    // Takes a request in on behalf of the tenant, or tells why it may not.
+    pub fn admit(&mut self, tenant: &str, now: Instant) -> std::result::Result<(), String> {
+        let quota = self.quota(tenant);
+        let usage = self.usage_mut(tenant);
+        while usage.admitted.front().map_or(false, |admitted| {
+            now.duration_since(*admitted) >= RATE_WINDOW
+        }) {
+            usage.admitted.pop_front();
+        }
+
+        if let Some(max_concurrency) = quota.max_concurrency {
+            if usage.active >= max_concurrency {
+                return Err(format!(
+                    "Tenant has {} requests in progress already",
+                    max_concurrency
+                ));
+            }
+        }
+        if let Some(max_per_minute) = quota.max_per_minute {
+            if usage.admitted.len() >= max_per_minute {
+                return Err(format!(
+                    "Tenant sent {} requests in the last minute already",
+                    max_per_minute
+                ));
+            }
+        }
+
+        usage.active += 1;
+        usage.admitted.push_back(now);
+        Ok(())
+    }
+
+    // Gives the tenant a request back once it was replied to.
+    pub fn release(&mut self, tenant: &str) {
+        let usage = self.usage_mut(tenant);
+        usage.active = usage.active.saturating_sub(1);
+    }
+
+    pub fn active(&self, tenant: &str) -> usize {
+        if self.overrides.contains_key(tenant) {
+            self.usage.get(tenant).map_or(0, |usage| usage.active)
+        } else {
+            self.shared.active
+        }
+    }
+
+    // Forgets tenants with nothing in progress nor admitted within the rate
+    // window.
+    pub fn purge_idle(&mut self, now: Instant) -> usize {
+        let before = self.usage.len();
+        self.usage.retain(|_, usage| {
+            usage.active > 0
+                || usage.admitted.back().map_or(false, |admitted| {
+                    now.duration_since(*admitted) < RATE_WINDOW
+                })
+        });
+        before - self.usage.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.usage.len()
+    }
+}
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas() -> TenantQuotas {
+        let mut overrides = HashMap::new();
+        overrides.insert(String::from("batch"), Quota::new(1, 0));
+        TenantQuotas::new(Quota::new(0, 2), overrides)
+    }
+
+    #[test]
+    fn parse_quota_override() {
+        let (tenant, quota) = Quota::parse_override("acme=4/120").unwrap();
+        assert_eq!(tenant, "acme");
+        assert_eq!(quota.max_concurrency, Some(4));
+        assert_eq!(quota.max_per_minute, Some(120));
+
+        let (_, quota) = Quota::parse_override("acme=0/60").unwrap();
+        assert_eq!(quota.max_concurrency, None);
+
+        assert!(Quota::parse_override("acme").is_err());
+        assert!(Quota::parse_override("=4/120").is_err());
+        assert!(Quota::parse_override("acme=4").is_err());
+        assert!(Quota::parse_override("acme=four/120").is_err());
+    }
+
+    #[test]
+    fn limit_concurrent_requests() {
+        let mut quotas = quotas();
+        let now = Instant::now();
+        assert!(quotas.admit("batch", now).is_ok());
+        assert!(quotas.admit("batch", now).is_err());
+        assert_eq!(quotas.active("batch"), 1);
+
+        quotas.release("batch");
+        assert!(quotas.admit("batch", now).is_ok());
+    }
+
+    #[test]
+    fn limit_requests_per_minute() {
+        let mut quotas = quotas();
+        let now = Instant::now();
+        assert!(quotas.admit("acme", now).is_ok());
+        quotas.release("acme");
+        assert!(quotas.admit("acme", now).is_ok());
+        quotas.release("acme");
+        assert!(quotas.admit("acme", now).is_err());
+
+        // tenants without a quota of their own share the default one
+        assert!(quotas.admit("other", now).is_err());
+        assert!(quotas.admit("batch", now).is_ok());
+
+        let later = now + Duration::from_secs(61);
+        assert!(quotas.admit("acme", later).is_ok());
+    }
+
+    #[test]
+    fn purge_idle_tenants() {
+        let mut overrides = HashMap::new();
+        overrides.insert(String::from("busy"), Quota::default());
+        overrides.insert(String::from("idle"), Quota::default());
+        let mut quotas = TenantQuotas::new(Quota::default(), overrides);
+        let now = Instant::now();
+        quotas.admit("busy", now).unwrap();
+        quotas.admit("idle", now).unwrap();
+        quotas.release("idle");
+        assert_eq!(quotas.purge_idle(now + Duration::from_secs(30)), 0);
+        assert_eq!(quotas.len(), 2);
+
+        // still busy ones are kept, however long ago they were admitted
+        assert_eq!(quotas.purge_idle(now + Duration::from_secs(61)), 1);
+        assert_eq!(quotas.active("busy"), 1);
+        assert_eq!(quotas.len(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+// How long requests may wait in the queue before more workers are started, and
+// how long a worker may sit idle before it is retired.
+pub const DEFAULT_SCALE_UP_AFTER: Duration = Duration::from_secs(1);
+pub const DEFAULT_SCALE_DOWN_AFTER: Duration = Duration::from_secs(30);
+
+// What the broker knows about its load when it considers scaling. Only workers
+// spawned by the broker count, since remote ones are up to whoever started them.
+#[derive(Debug, Clone, Default)]
+pub struct Load {
+    pub spawned: usize,
+    pub starting: usize,
+    pub queued: usize,
+    pub longest_wait: Duration,
+}
+
+// Keeps the number of spawned workers between min and max instances, starting
+// workers while requests pile up and retiring those left idle for long.
+#[derive(Debug, Clone)]
+pub struct ScalingPolicy {
+    pub min_instances: usize,
+    pub max_instances: usize,
+    pub scale_up_after: Duration,
+    pub scale_down_after: Duration,
+}
+
+impl ScalingPolicy {
+    pub fn new(min_instances: usize, max_instances: usize) -> ScalingPolicy {
+        let instance = ScalingPolicy {
+            min_instances: min_instances,
+            max_instances: max_instances,
+            scale_up_after: DEFAULT_SCALE_UP_AFTER,
+            scale_down_after: DEFAULT_SCALE_DOWN_AFTER,
+        };
+        instance
+    }
+
+    pub fn workers_to_start(&self, load: &Load) -> usize {
+        let missing = self.min_instances.saturating_sub(load.spawned);
+        if missing > 0 {
+            return missing;
+        }
+
+        // a queue deeper than the workers there are would not drain soon anyway,
+        // so there is no point in waiting for it
+        let piling_up = load.queued > 0
+            && (load.queued >= load.spawned || load.longest_wait >= self.scale_up_after);
+        if !piling_up {
+            return 0;
+        }
+
+        // workers still starting up will take some of the queue already
+        let wanted = load.queued.saturating_sub(load.starting);
+        let room = self.max_instances.saturating_sub(load.spawned);
+        wanted.min(room)
+    }
+
+    pub fn workers_to_retire(&self, load: &Load, idle: usize) -> usize {
+        if load.queued > 0 {
+            return 0;
+        }
+        let surplus = load.spawned.saturating_sub(self.min_instances);
+        idle.min(surplus)
+    }
+}
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(spawned: usize, starting: usize, queued: usize, longest_wait: u64) -> Load {
+        Load {
+            spawned: spawned,
+            starting: starting,
+            queued: queued,
+            longest_wait: Duration::from_secs(longest_wait),
+        }
+    }
+
+    #[test]
+    fn keep_min_instances() {
+        let policy = ScalingPolicy::new(2, 4);
+        assert_eq!(policy.workers_to_start(&load(0, 0, 0, 0)), 2);
+        assert_eq!(policy.workers_to_start(&load(2, 0, 0, 0)), 0);
+        assert_eq!(policy.workers_to_retire(&load(2, 0, 0, 0), 2), 0);
+    }
+
+    #[test]
+    fn scale_up_as_requests_pile_up() {
+        let policy = ScalingPolicy::new(2, 4);
+        // a short queue is given some time to drain
+        assert_eq!(policy.workers_to_start(&load(2, 0, 1, 0)), 0);
+        assert_eq!(policy.workers_to_start(&load(2, 0, 1, 2)), 1);
+        // a deep one is not
+        assert_eq!(policy.workers_to_start(&load(2, 0, 3, 0)), 2);
+        // nor beyond max instances
+        assert_eq!(policy.workers_to_start(&load(3, 0, 5, 2)), 1);
+        assert_eq!(policy.workers_to_start(&load(4, 0, 5, 2)), 0);
+    }
+
+    #[test]
+    fn count_workers_still_starting() {
+        let policy = ScalingPolicy::new(1, 8);
+        assert_eq!(policy.workers_to_start(&load(3, 2, 3, 2)), 1);
+        assert_eq!(policy.workers_to_start(&load(3, 3, 3, 2)), 0);
+    }
+
+    #[test]
+    fn scale_down_idle_workers() {
+        let policy = ScalingPolicy::new(2, 4);
+        assert_eq!(policy.workers_to_retire(&load(4, 0, 0, 0), 1), 1);
+        assert_eq!(policy.workers_to_retire(&load(4, 0, 0, 0), 4), 2);
+        // not while requests wait
+        assert_eq!(policy.workers_to_retire(&load(4, 0, 1, 0), 4), 0);
+    }
+}
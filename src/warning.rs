@@ -0,0 +1,121 @@
+use super::error::{error, ErrorKind, Result};
+use super::protocol::{OnWarning, WarningAction};
+use regex::Regex;
+
+// Which warnings raised while rendering (e.g. by a failing script) a request
+// cares about, and what to do with them. Without trigger words nor patterns every
+// warning matters; otherwise only those containing any of the words, regardless
+// of case, or matching any of the patterns.
+#[derive(Debug, Clone)]
+pub struct WarningPolicy {
+    pub action: WarningAction,
+    words: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl WarningPolicy {
+    pub fn new(on_warning: Option<&OnWarning>) -> Result<WarningPolicy> {
+        let on_warning = match on_warning {
+            Some(on_warning) => on_warning,
+            None => {
+                return Ok(WarningPolicy {
+                    action: WarningAction::default(),
+                    words: Vec::new(),
+                    patterns: Vec::new(),
+                })
+            }
+        };
+
+        let mut patterns = Vec::new();
+        for pattern in &on_warning.trigger_patterns {
+            match Regex::new(pattern) {
+                Ok(compiled) => patterns.push(compiled),
+                Err(reason) => {
+                    return error(
+                        ErrorKind::Validation,
+                        format!("Invalid trigger pattern {}", pattern).as_str(),
+                        reason,
+                    )
+                }
+            }
+        }
+        let instance = WarningPolicy {
+            action: on_warning.action,
+            words: on_warning
+                .trigger_words
+                .iter()
+                .map(|word| word.to_lowercase())
+                .collect(),
+            patterns: patterns,
+        };
+        Ok(instance)
+    }
+
+    pub fn matches(&self, warning: &str) -> bool {
+        if self.words.is_empty() && self.patterns.is_empty() {
+            return true;
+        }
+        let warning_lowercase = warning.to_lowercase();
+        self.words
+            .iter()
+            .any(|word| warning_lowercase.contains(word.as_str()))
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.is_match(warning))
+    }
+
+    // Warnings the request cares about, in the order they were raised.
+    pub fn select(&self, warnings: Vec<String>) -> Vec<String> {
+        warnings
+            .into_iter()
+            .filter(|warning| self.matches(warning))
+            .collect()
+    }
+}
+
+// Unit testing
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn on_warning(action: WarningAction, words: &[&str], patterns: &[&str]) -> OnWarning {
+        OnWarning {
+            action: action,
+            trigger_words: words.iter().map(|word| word.to_string()).collect(),
+            trigger_patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn match_any_warning_by_default() {
+        let policy = WarningPolicy::new(None).unwrap();
+        assert_eq!(policy.action, WarningAction::Abort);
+        assert!(policy.matches("Warning: Failed to load about:blank"));
+    }
+
+    #[test]
+    fn match_trigger_words_and_patterns() {
+        let policy = WarningPolicy::new(Some(&on_warning(
+            WarningAction::Collect,
+            &["Error"],
+            &[r"^Failed to load .*\.css"],
+        )))
+        .unwrap();
+        assert!(policy.matches("TypeError: undefined is not a function"));
+        assert!(policy.matches("Failed to load file:///tmp/site.css"));
+        assert!(!policy.matches("Failed to load file:///tmp/logo.png"));
+        assert_eq!(
+            policy.select(vec![String::from("Some error"), String::from("Unrelated")]),
+            vec![String::from("Some error")]
+        );
+    }
+
+    #[test]
+    fn reject_invalid_patterns() {
+        let result = WarningPolicy::new(Some(&on_warning(WarningAction::Abort, &[], &["("])));
+        assert_eq!(result.unwrap_err().kind, ErrorKind::Validation);
+    }
+}
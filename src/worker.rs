@@ -1,72 +1,199 @@
-use super::error::Result;
-use super::helpers::get_uid;
-use super::helpers::zmq_helpers::{assert_empty, recv_string, send, send_multipart};
+use super::error::{AnyError, ErrorKind, Result};
+use super::helpers::fs_helpers::TempDir;
+use super::helpers::zmq_helpers::{send_multipart, Identity};
+use super::helpers::{get_hostname, get_uid};
 use super::pdf::{get_pdf_setting_value, PDF_GLOBAL_SETTINGS, PDF_OBJECT_SETTINGS};
 use super::protocol::*;
-use serde_json::Value;
-use std::fs::File;
-use std::io;
+use super::warning::WarningPolicy;
+use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use url::Url;
 use wkhtmltopdf::PdfApplication;
 use zmq;
 
 const MSG_FAILED_TO_ACQUIRE_LOCK_OF_SERVICE_SOCKET: &str =
     "failed to acquire lock of service socket";
+const MSG_FAILED_TO_ACQUIRE_LOCK_OF_DIAGNOSTICS: &str = "failed to acquire lock of diagnostics";
+
+// What the render watchdog is told by the event loop.
+#[derive(Debug)]
+enum Watch {
+    Render(Identity, Duration),
+    Workdir(PathBuf),
+    Done,
+}
+
+// Why a render failed, replied to the client rather than taking the worker down.
+#[derive(Debug)]
+struct Failure {
+    stage: RenderStage,
+    reason: AnyError,
+    diagnostics: Option<Diagnostics>,
+}
+
+impl Failure {
+    fn new(stage: RenderStage, kind: ErrorKind, message: String) -> Failure {
+        Failure::caused_by(stage, AnyError::without_parent(kind, message.as_str()))
+    }
+
+    fn caused_by(stage: RenderStage, reason: AnyError) -> Failure {
+        Failure {
+            stage: stage,
+            reason: reason,
+            diagnostics: None,
+        }
+    }
+
+    fn with_diagnostics(self, diagnostics: &Diagnostics) -> Failure {
+        Failure {
+            diagnostics: Some(diagnostics.clone()),
+            ..self
+        }
+    }
+
+    fn bad_request(message: String) -> Failure {
+        Failure::new(RenderStage::Parse, ErrorKind::Validation, message)
+    }
+}
 
 #[derive(Debug)]
 pub struct Worker {
     id: u32,
     stop_signal: Arc<AtomicBool>,
+    rendering: Arc<AtomicBool>,
+    broker_endpoint: String,
     output_dir: PathBuf,
     timeout: Duration,
+    heartbeat_interval: Duration,
 }
 
 impl Worker {
     pub fn new(
         id: u32,
         stop_signal: Arc<AtomicBool>,
+        broker_endpoint: &str,
         output_dir: &Path,
         timeout: Duration,
+        heartbeat_interval: Duration,
     ) -> Worker {
         let instance = Worker {
             id: id,
             stop_signal: stop_signal,
+            rendering: Arc::new(AtomicBool::new(false)),
+            broker_endpoint: String::from(broker_endpoint),
             output_dir: PathBuf::from(output_dir),
             timeout: timeout,
+            heartbeat_interval: heartbeat_interval,
         };
         instance
     }
 
     pub fn run<'a, F: 'a + Fn()>(&'a mut self, on_ready: F) -> Result<()> {
-        let service_socket_guard =
-            create_service_socket(self.id).expect("failed to get a service socket guard");
-        let (heartbeat_tx, heartbeat_rx) = channel::<()>();
-        self.watch_eventloop(heartbeat_rx);
-        self.run_eventloop(service_socket_guard.clone(), heartbeat_tx, on_ready)
+        let service_socket_guard = create_service_socket(self.id, &self.broker_endpoint)?;
+        let (watchdog_tx, watchdog_rx) = channel::<Watch>();
+        self.watch_renders(service_socket_guard.clone(), watchdog_rx);
+        self.run_eventloop(service_socket_guard.clone(), watchdog_tx, on_ready)
     }
 
-    fn watch_eventloop(&self, heartbeat_rx: Receiver<()>) {
+    fn watch_renders(
+        &self,
+        service_socket_guard: Arc<Mutex<zmq::Socket>>,
+        watchdog_rx: Receiver<Watch>,
+    ) {
         let id = self.id;
-        let timeout = self.timeout;
+        let idle_check = self.heartbeat_interval;
         let stop_signal = self.stop_signal.clone();
-
+        let rendering = self.rendering.clone();
+
+        // armed for one render at a time, since a hung QtWebKit cannot be
+        // interrupted, the client is told and the process exits to be replaced;
+        // so does it when broker cancels the render, checked every heartbeat,
+        // which broker only does to workers it spawned; meanwhile, it is what
+        // keeps telling broker this worker is alive, since the event loop is
+        // busy rendering
         thread::spawn(move || {
+            let mut watching: Option<(Identity, Duration, Instant)> = None;
+            let mut workdir: Option<PathBuf> = None;
             while !stop_signal.load(Ordering::SeqCst) {
-                if heartbeat_rx.recv_timeout(timeout).is_err() {
-                    println!(
-                        "[#{}] Hugging for more than {}s and will terminate now",
-                        id,
-                        timeout.as_secs()
-                    );
-                    process::exit(666);
+                let wait = match &watching {
+                    Some((_, _, deadline)) => deadline
+                        .saturating_duration_since(Instant::now())
+                        .min(idle_check),
+                    None => idle_check,
+                };
+                match watchdog_rx.recv_timeout(wait) {
+                    Ok(Watch::Render(client_id, timeout)) => {
+                        // only a timeout from the command line can be that far off
+                        let deadline = Instant::now().checked_add(timeout).unwrap_or_else(|| {
+                            Instant::now() + Duration::from_millis(MAX_TIMEOUT_MS)
+                        });
+                        watching = Some((client_id, timeout, deadline));
+                        workdir = None;
+                    }
+                    Ok(Watch::Workdir(path)) => workdir = Some(path),
+                    Ok(Watch::Done) => {
+                        watching = None;
+                        workdir = None;
+                    }
+                    Err(RecvTimeoutError::Timeout) => match watching.take() {
+                        Some((client_id, timeout, deadline)) if deadline <= Instant::now() => {
+                            let failure = Failure::new(
+                                RenderStage::Convert,
+                                ErrorKind::Timeout,
+                                format!(
+                                    "Rendering took more than {}ms and was aborted",
+                                    timeout.as_millis()
+                                ),
+                            );
+                            println!(
+                                "[#{}] Reply to client #{}: {}",
+                                id, client_id, failure.reason
+                            );
+                            send_client_reply_with_failure(
+                                service_socket_guard.clone(),
+                                &client_id,
+                                &failure,
+                            );
+                            // Waits just a bit to let message goes to client
+                            thread::sleep(Duration::from_millis(50));
+                            remove_workdir(id, workdir.take());
+                            println!("[#{}] Will terminate now to be replaced", id);
+                            process::exit(failure.reason.kind.exit_code());
+                        }
+                        Some((client_id, timeout, deadline)) => {
+                            if !send_heartbeat(service_socket_guard.clone()) {
+                                println!("[#{}] Failed sending <HEARTBEAT> to broker", id);
+                            }
+                            if recv_cancel(service_socket_guard.clone(), &rendering) {
+                                // broker replied to the client already
+                                println!(
+                                    "[#{}] Rendering of client #{} was cancelled",
+                                    id, client_id
+                                );
+                                send_messsage(
+                                    service_socket_guard.clone(),
+                                    &[MSG_WORKER_IS_GONE],
+                                    "failed to send <GONE> response",
+                                );
+                                // Waits just a bit to let message goes to broker
+                                thread::sleep(Duration::from_millis(50));
+                                remove_workdir(id, workdir.take());
+                                println!("[#{}] Will terminate now to be replaced", id);
+                                process::exit(0);
+                            }
+                            watching = Some((client_id, timeout, deadline));
+                        }
+                        None => {}
+                    },
+                    Err(RecvTimeoutError::Disconnected) => break,
                 }
             }
         });
@@ -75,7 +202,7 @@ impl Worker {
     fn run_eventloop<'a, F: 'a + Fn()>(
         &'a mut self,
         service_socket_guard: Arc<Mutex<zmq::Socket>>,
-        heartbeat_tx: Sender<()>,
+        watchdog_tx: Sender<Watch>,
         on_ready: F,
     ) -> Result<()> {
         // Enclosing scope for PdfApplication
@@ -84,369 +211,622 @@ impl Worker {
 
             // worker is ready, so notify it
             on_ready();
-            send_messsage(
-                service_socket_guard.clone(),
-                "READY",
-                "failed sending <READY> to broker",
-            );
+            self.send_ready(service_socket_guard.clone());
+
+            // broker is given up on after missing that many heartbeats in a row, then
+            // reconnected to with a backoff, so a restarted broker gets workers back
+            let mut liveness = HEARTBEAT_LIVENESS;
+            let mut reconnect_interval = self.heartbeat_interval;
+            let mut heartbeat_at = Instant::now();
 
             while !self.stop_signal.load(Ordering::SeqCst) {
-                // send heartbeat to sign it's not hugging
-                if let Err(reason) = heartbeat_tx.send(()) {
-                    println!(
-                        "[#{}] Something went weird with the heartbeat monitoring: {:?}",
-                        self.id, reason
-                    );
-                    break;
+                // heartbeats go out from here while idle, same as broker does it, so
+                // nothing else waits for the socket meanwhile
+                if Instant::now() >= heartbeat_at {
+                    if !send_heartbeat(service_socket_guard.clone()) {
+                        println!("[#{}] Failed sending <HEARTBEAT> to broker", self.id);
+                    }
+                    heartbeat_at = Instant::now() + self.heartbeat_interval;
                 }
 
                 // try to grap some reply message from broker, which might be a command or a
                 // client ID followed by an actual request
-                let message = match recv_message(service_socket_guard.clone()) {
-                    Ok(received) => received.unwrap(),
-                    Err(_) => continue,
+                let wait = heartbeat_at.saturating_duration_since(Instant::now());
+                let frames = match recv_frames(service_socket_guard.clone(), wait) {
+                    Ok(Some(received)) => received,
+                    // a signal interrupts polling, its flag is looked at right away
+                    Err(zmq::Error::EINTR) => continue,
+                    Err(reason) => {
+                        println!("[#{}] Failed receiving from broker: {}", self.id, reason);
+                        continue;
+                    }
+                    Ok(None) => {
+                        liveness -= 1;
+                        if liveness == 0 {
+                            println!(
+                                "[#{}] Broker missed its heartbeats, will reconnect in {}ms",
+                                self.id,
+                                reconnect_interval.as_millis()
+                            );
+                            thread::sleep(reconnect_interval);
+                            if reconnect_interval < self.heartbeat_interval * 32 {
+                                reconnect_interval *= 2;
+                            }
+                            reconnect_service_socket(
+                                service_socket_guard.clone(),
+                                self.id,
+                                &self.broker_endpoint,
+                            )
+                            .expect("failed reconnecting to broker");
+                            self.send_ready(service_socket_guard.clone());
+                            liveness = HEARTBEAT_LIVENESS;
+                        }
+                        continue;
+                    }
                 };
+                liveness = HEARTBEAT_LIVENESS;
+                reconnect_interval = self.heartbeat_interval;
+
+                // whatever does not look like a broker envelope is dropped, the
+                // worker keeps serving
+                if frames.len() < 2 || !frames[0].is_empty() {
+                    println!(
+                        "[#{}] Discarded malformed envelope from broker: {} frames",
+                        self.id,
+                        frames.len()
+                    );
+                    continue;
+                }
+                let message = String::from_utf8_lossy(&frames[1]).to_string();
+                if message == MSG_HEARTBEAT {
+                    continue;
+                }
                 println!("[#{}] Message: {}", self.id, message);
 
                 // -- from broker
-                if message == "STOP" {
+                // a render cancelled right as it finished has nothing left to abort
+                if message == MSG_CANCEL {
+                    self.send_ready(service_socket_guard.clone());
+                    continue;
+                }
+                if message == MSG_STOP_WORKER {
                     send_messsage(
                         service_socket_guard.clone(),
-                        "GONE",
+                        &[MSG_WORKER_IS_GONE],
                         "failed to send <GONE> response",
                     );
                     break;
                 }
 
                 // -- from client
-                let (client_id, request) =
-                    self.read_client_request(service_socket_guard.clone(), message);
-                println!("[#{}] Client #{} request: {}", self.id, client_id, request);
+                // broker is told the worker is ready again, so it does not wait for
+                // a reply to a request that never made it
+                let (client_id, request) = match self.read_client_request(frames) {
+                    Some(received) => received,
+                    None => {
+                        println!(
+                            "[#{}] Discarded malformed request envelope from broker",
+                            self.id
+                        );
+                        self.send_ready(service_socket_guard.clone());
+                        continue;
+                    }
+                };
+                println!(
+                    "[#{}] Client #{} request: {}",
+                    self.id,
+                    client_id,
+                    String::from_utf8_lossy(&request)
+                );
 
                 self.handle_client_request(
                     service_socket_guard.clone(),
+                    &watchdog_tx,
                     &client_id,
                     &request,
                     &mut pdf_app,
                 );
+                if let Err(reason) = watchdog_tx.send(Watch::Done) {
+                    println!(
+                        "[#{}] Something went weird with the render watchdog: {:?}",
+                        self.id, reason
+                    );
+                    break;
+                }
             }
         }
 
+        // stopped by a signal of its own rather than by broker, which is told
+        // so it stops sending requests this way
+        if self.stop_signal.load(Ordering::SeqCst) {
+            send_messsage(
+                service_socket_guard.clone(),
+                &[MSG_WORKER_IS_GONE],
+                "failed to send <GONE> response",
+            );
+        }
+
         println!("[#{}] Stopping...", self.id);
-        finish_service_socket(service_socket_guard.clone());
+        finish_service_socket(service_socket_guard.clone(), &self.broker_endpoint);
         println!("[#{}] Disconnected from broker to stop", self.id);
 
         Ok(())
     }
 
-    fn read_client_request(
-        &self,
-        service_socket_guard: Arc<Mutex<zmq::Socket>>,
-        message: String,
-    ) -> (String, String) {
-        let service_socket = service_socket_guard
-            .lock()
-            .expect(MSG_FAILED_TO_ACQUIRE_LOCK_OF_SERVICE_SOCKET);
-        // read multipart envelope from client as:
-        //   CLIENT, EMPTY, REQUEST
-        let client_id = message;
-        assert_empty(
-            &service_socket,
-            "failed reading 1nd <EMPTY> of client's envelope",
+    fn send_ready(&self, service_socket_guard: Arc<Mutex<zmq::Socket>>) {
+        let capabilities = WorkerCapabilities::new(&get_hostname(), self.id);
+        send_messsage(
+            service_socket_guard,
+            &[MSG_WORKER_IS_READY, capabilities.to_json().as_str()],
+            "failed sending <READY> to broker",
         );
-        let request = recv_string(
-            &service_socket,
-            "failed reading <REQUEST> from client's envelope",
-        );
-        (client_id, request)
+    }
+
+    fn read_client_request(&self, frames: Vec<Vec<u8>>) -> Option<(Identity, Vec<u8>)> {
+        // read multipart envelope from client as:
+        //   EMPTY, CLIENT, EMPTY, REQUEST
+        if frames.len() != 4 || !frames[2].is_empty() {
+            return None;
+        }
+        let mut frames = frames.into_iter().skip(1);
+        let client_id = Identity::from(frames.next()?);
+        // whatever the client sent is up to the request parsing to judge
+        let request = frames.nth(1)?;
+        Some((client_id, request))
     }
 
     fn handle_client_request(
         &self,
         service_socket_guard: Arc<Mutex<zmq::Socket>>,
-        client_id: &String,
-        request: &String,
+        watchdog_tx: &Sender<Watch>,
+        client_id: &Identity,
+        request: &[u8],
         pdf_app: &mut PdfApplication,
     ) {
-        // parse request body
-        let payload: Value =
-            serde_json::from_str(request.as_str()).expect("failed parsing request as JSON");
-        println!("[#{}] Client #{} payload: {}", self.id, client_id, payload);
-
-        if payload == Value::Null {
-            let err_msg = format!("Payload cannot be null");
-            println!("{}", err_msg.as_str());
-
-            send_client_reply_with_error(
+        // every failure a render might recover from is replied to the client, so
+        // only a hung or crashed QtWebKit takes the worker down
+        let rendered = self.render(watchdog_tx, client_id, request, pdf_app);
+        // past this point broker might send the next request, which is not the
+        // watchdog's to read
+        self.rendering.store(false, Ordering::SeqCst);
+        match rendered {
+            Ok((reply, attachment)) => send_client_reply_with_success(
                 service_socket_guard.clone(),
                 &client_id,
-                REP_400_BAD_REQUEST,
-                &err_msg,
-            );
-            return;
+                &reply,
+                attachment,
+            ),
+            Err(failure) => {
+                println!(
+                    "[#{}] Reply to client #{}: {} failed with {} error: {}",
+                    self.id, client_id, failure.stage, failure.reason.kind, failure.reason
+                );
+                send_client_reply_with_failure(service_socket_guard.clone(), &client_id, &failure);
+            }
         }
+    }
+
+    fn render(
+        &self,
+        watchdog_tx: &Sender<Watch>,
+        client_id: &Identity,
+        request: &[u8],
+        pdf_app: &mut PdfApplication,
+    ) -> std::result::Result<(RenderReply, Option<Vec<u8>>), Failure> {
+        // parse request body
+        let payload = std::str::from_utf8(request)
+            .map_err(|reason| Failure::bad_request(format!("Request is not UTF-8: {}", reason)))
+            .and_then(|request| {
+                RenderRequest::from_json(request)
+                    .map_err(|reason| Failure::caused_by(RenderStage::Parse, reason))
+            })?;
+        println!(
+            "[#{}] Client #{} payload: {:?}",
+            self.id, client_id, payload
+        );
+
+        // from now on rendering is on the clock
+        watchdog_tx
+            .send(Watch::Render(
+                client_id.clone(),
+                payload.timeout(self.timeout),
+            ))
+            .expect("failed arming render watchdog");
+        self.rendering.store(true, Ordering::SeqCst);
 
         // parse the actual request
         let message_id = get_uid();
-        let url = match payload["url"].as_str() {
-            Some(s) => match Url::from_str(&s) {
-                Ok(parsed) => parsed,
-                Err(_) => {
-                    let err_msg = format!("Cannot parse URL: {}", payload);
-                    println!(
-                        "[#{}] Reply to client #{}: {}",
-                        self.id,
-                        client_id,
-                        err_msg.as_str()
-                    );
-
-                    send_client_reply_with_error(
-                        service_socket_guard.clone(),
-                        &client_id,
-                        REP_400_BAD_REQUEST,
-                        &err_msg,
-                    );
-                    return;
-                }
-            },
-            None => {
-                let err_msg = format!("URL is missing in request payload: {}", payload);
-                println!(
-                    "[#{}] Reply to client #{}: {}",
-                    self.id,
-                    client_id,
-                    err_msg.as_str()
-                );
 
-                send_client_reply_with_error(
-                    service_socket_guard.clone(),
-                    &client_id,
-                    REP_400_BAD_REQUEST,
-                    &err_msg,
-                );
-                return;
+        // inline content lives in a private directory for as long as this request
+        let workdir = if payload.has_inline_content() {
+            let workdir = TempDir::new(format!("wk-{}-{}", self.id, message_id).as_str()).map_err(
+                |reason| {
+                    Failure::new(
+                        RenderStage::Load,
+                        ErrorKind::Io,
+                        format!("Cannot create temp directory: {}", reason),
+                    )
+                },
+            )?;
+            watchdog_tx
+                .send(Watch::Workdir(workdir.path().to_path_buf()))
+                .expect("failed telling render watchdog about temp directory");
+            for (name, encoded) in &payload.assets {
+                let content = base64::decode(encoded).map_err(|reason| {
+                    Failure::bad_request(format!("Cannot decode asset {}: {}", name, reason))
+                })?;
+                workdir.write(name, &content).map_err(|reason| {
+                    Failure::new(
+                        RenderStage::Load,
+                        ErrorKind::Io,
+                        format!("Cannot write asset {}: {}", name, reason),
+                    )
+                })?;
             }
+            Some(workdir)
+        } else {
+            None
         };
+
+        let mut pages = Vec::new();
+        let page_objects = payload
+            .page_objects()
+            .map_err(|reason| Failure::caused_by(RenderStage::Parse, reason))?;
+        for (i, page_object) in page_objects.into_iter().enumerate() {
+            let url = match &page_object.source {
+                PageSource::Url(page) => Url::from_str(page)
+                    .map_err(|_| Failure::bad_request(format!("Cannot parse URL: {}", page)))?,
+                PageSource::Html(html) => {
+                    let filepath = workdir
+                        .as_ref()
+                        .expect("missing temp directory for inline HTML")
+                        .write(format!("_wk_page_{}.html", i).as_str(), html.as_bytes())
+                        .map_err(|reason| {
+                            Failure::new(
+                                RenderStage::Load,
+                                ErrorKind::Io,
+                                format!("Cannot write inline HTML: {}", reason),
+                            )
+                        })?;
+                    Url::from_file_path(&filepath).map_err(|_| {
+                        Failure::new(
+                            RenderStage::Load,
+                            ErrorKind::Io,
+                            format!("Cannot build URL for {}", filepath.display()),
+                        )
+                    })?
+                }
+            };
+            pages.push((url, page_object.settings));
+        }
         let filepath = self.output_dir.join(Path::new(
             format!("req-{}-{}.pdf", self.id, message_id).as_str(),
         ));
 
+        let policy = WarningPolicy::new(payload.on_warning.as_ref())
+            .map_err(|reason| Failure::caused_by(RenderStage::Parse, reason))?;
+        let recorded = Arc::new(Mutex::new(Diagnostics::default()));
+
         // actual pdf building
-        unsafe {
+        let mut pdf_content = Vec::new();
+        let diagnostics = unsafe {
             let pdf_builder = pdf_app.builder();
 
             // global converter settings
-            let mut pdf_global_settings = pdf_builder
-                .global_settings()
-                .expect("failed to create global settings");
-
-            if let Value::Object(json_global_settings) = &payload["global"] {
-                for (json_key, json_value) in json_global_settings {
-                    if let Some(pdf_setting) = PDF_GLOBAL_SETTINGS.get(json_key.as_str()) {
-                        match get_pdf_setting_value(pdf_setting, json_value) {
-                            Ok(v) => pdf_global_settings.set(json_key, v.as_str()).expect(
-                                format!("failed setting global option {}", &json_key).as_str(),
-                            ),
-                            Err(e) => {
-                                send_client_reply_with_error(
-                                    service_socket_guard.clone(),
-                                    &client_id,
-                                    REP_400_BAD_REQUEST,
-                                    &e.details,
-                                );
-                                return;
-                            }
-                        }
-                    }
+            let mut pdf_global_settings = pdf_builder.global_settings().map_err(|reason| {
+                Failure::new(
+                    RenderStage::Convert,
+                    ErrorKind::Render,
+                    format!("Cannot create global settings: {}", reason),
+                )
+            })?;
+
+            for (json_key, json_value) in &payload.global {
+                if let Some(pdf_setting) = PDF_GLOBAL_SETTINGS.get(json_key.as_str()) {
+                    let value = get_pdf_setting_value(pdf_setting, json_value)
+                        .map_err(|reason| Failure::caused_by(RenderStage::Parse, reason))?;
+                    pdf_global_settings
+                        .set(json_key, value.as_str())
+                        .map_err(|reason| {
+                            Failure::new(
+                                RenderStage::Convert,
+                                ErrorKind::Render,
+                                format!("Cannot set global option {}: {}", json_key, reason),
+                            )
+                        })?;
                 }
             }
 
-            // object page settings
-            let mut pdf_object_settings = pdf_builder
-                .object_settings()
-                .expect("failed to create object settings");
+            let mut pdf_converter = pdf_global_settings.create_converter();
 
-            if let Value::Object(json_object_setting) = &payload["object"] {
-                for (json_key, json_value) in json_object_setting {
+            // object page settings, one per page in the order they were given
+            for (url, settings) in &pages {
+                let mut pdf_object_settings = pdf_builder.object_settings().map_err(|reason| {
+                    Failure::new(
+                        RenderStage::Convert,
+                        ErrorKind::Render,
+                        format!("Cannot create object settings: {}", reason),
+                    )
+                })?;
+
+                for (json_key, json_value) in settings {
                     if let Some(pdf_setting) = PDF_OBJECT_SETTINGS.get(json_key.as_str()) {
-                        match get_pdf_setting_value(pdf_setting, json_value) {
-                            Ok(v) => pdf_object_settings.set(json_key, v.as_str()).expect(
-                                format!("failed setting object option {}", &json_key).as_str(),
-                            ),
-                            Err(e) => {
-                                send_client_reply_with_error(
-                                    service_socket_guard.clone(),
-                                    &client_id,
-                                    REP_400_BAD_REQUEST,
-                                    &e.details,
-                                );
-                                return;
-                            }
-                        }
+                        let value = get_pdf_setting_value(pdf_setting, json_value)
+                            .map_err(|reason| Failure::caused_by(RenderStage::Parse, reason))?;
+                        pdf_object_settings
+                            .set(json_key, value.as_str())
+                            .map_err(|reason| {
+                                Failure::new(
+                                    RenderStage::Convert,
+                                    ErrorKind::Render,
+                                    format!("Cannot set object option {}: {}", json_key, reason),
+                                )
+                            })?;
                     }
                 }
-            }
 
-            let mut pdf_converter = pdf_global_settings.create_converter();
-            pdf_converter.add_page_object(pdf_object_settings, url.as_str());
-
-            // warning behavior
-            let mut on_warning_action = String::from("abort");
-            let on_warning_trigger_words: Vec::<String>;
-            if let Value::Object(json_on_warning) = &payload["onWarning"] {
-                if let Some(action) = json_on_warning["action"].as_str() {
-                    on_warning_action = action.to_owned();
-                }
+                pdf_converter.add_page_object(pdf_object_settings, url.as_str());
             }
 
+            // everything reported is kept for the reply; warnings are only looked at
+            // once conversion is over, since it cannot be interrupted halfway anyway
+            let started = Instant::now();
             let local_id = self.id;
-            let local_client_id = client_id.clone();
-            let local_service_socket_guard = service_socket_guard.clone();
+            let local_recorded = recorded.clone();
             pdf_converter.set_warning_callback(Some(Box::new(move |warn| {
                 println!("[#{}] Warning: {}", local_id, warn);
-                if on_warning_action == "abort" {
-                    send_client_reply_with_error(
-                        local_service_socket_guard.clone(),
-                        &local_client_id,
-                        REP_502_BAD_GATEWAY,
-                        &warn,
-                    );
-                    // Waits just a bit to let message goes to client
-                    thread::sleep(Duration::from_millis(50));
-                    panic!(
-                        "worker #{} for client #{} is aborting due to potential JavaScript error",
-                        local_id, local_client_id
-                    );
-                }
+                local_recorded
+                    .lock()
+                    .expect(MSG_FAILED_TO_ACQUIRE_LOCK_OF_DIAGNOSTICS)
+                    .warnings
+                    .push(warn);
             })));
 
-            // build
-            let mut pdf_out = pdf_converter.convert().expect(
-                format!(
-                    "failed to convert {} to {}",
-                    url,
-                    filepath.to_str().unwrap()
+            // build, where pages that cannot be loaded make it fail too
+            let converted = pdf_converter.convert();
+            let mut diagnostics = recorded
+                .lock()
+                .expect(MSG_FAILED_TO_ACQUIRE_LOCK_OF_DIAGNOSTICS)
+                .clone();
+            diagnostics.elapsed_ms = started.elapsed().as_millis() as u64;
+
+            let mut pdf_out = converted.map_err(|reason| {
+                println!("[#{}] Error: {}", local_id, reason);
+                diagnostics.errors.push(reason.to_string());
+                Failure::new(
+                    RenderStage::Convert,
+                    ErrorKind::Render,
+                    format!("Cannot convert {} page(s): {}", pages.len(), reason),
                 )
-                .as_str(),
-            );
+                .with_diagnostics(&diagnostics)
+            })?;
+
+            pdf_out.read_to_end(&mut pdf_content).map_err(|reason| {
+                Failure::new(
+                    RenderStage::Convert,
+                    ErrorKind::Io,
+                    format!("Cannot read PDF output: {}", reason),
+                )
+                .with_diagnostics(&diagnostics)
+            })?;
+            diagnostics
+        };
 
-            // save
-            let mut pdf_file = File::create(&filepath)
-                .expect(format!("failed to create {}", filepath.to_str().unwrap()).as_str());
-            let pdf_bytes =
-                io::copy(&mut pdf_out, &mut pdf_file).expect("failed to write to basic.pdf");
-            println!(
-                "[#{}] Wrote {} bytes to file: {}",
-                self.id,
-                pdf_bytes,
-                filepath.to_str().unwrap()
-            );
+        let warnings = policy.select(diagnostics.warnings.clone());
+        if policy.action == WarningAction::Abort && !warnings.is_empty() {
+            return Err(Failure::new(
+                RenderStage::Convert,
+                ErrorKind::Render,
+                format!("Aborted on warning: {}", warnings.join("; ")),
+            )
+            .with_diagnostics(&diagnostics));
         }
 
         println!(
-            "[#{}] PDF built for client #{}: {}",
+            "[#{}] PDF built for client #{}: {} bytes",
             self.id,
             client_id,
-            filepath.to_str().unwrap()
+            pdf_content.len()
         );
 
-        // TODO: reply with pdf binary content instead of this dummy message
-        let content = format!("PDF saved at output directory");
+        // save
+        let saved_path = if payload.output.saves_file() {
+            let write_failure = |reason: std::io::Error| {
+                Failure::new(
+                    RenderStage::Write,
+                    ErrorKind::Io,
+                    format!("Cannot write {}: {}", filepath.display(), reason),
+                )
+            };
+            let mut pdf_file = File::create(&filepath).map_err(write_failure)?;
+            pdf_file.write_all(&pdf_content).map_err(write_failure)?;
+            println!(
+                "[#{}] Wrote {} bytes to file: {}",
+                self.id,
+                pdf_content.len(),
+                filepath.to_str().unwrap()
+            );
+            Some(String::from(filepath.to_str().unwrap()))
+        } else {
+            None
+        };
 
-        send_client_reply_with_success(service_socket_guard.clone(), &client_id, &content);
+        // reply, with pdf binary content attached if asked so
+        let mut reply = RenderReply::success("PDF built", saved_path, pdf_content.len());
+        if policy.action == WarningAction::Collect {
+            reply.warnings = warnings;
+        }
+        reply.diagnostics = Some(diagnostics);
+        let attachment = if payload.output.returns_content() {
+            Some(pdf_content)
+        } else {
+            None
+        };
+        Ok((reply, attachment))
     }
 }
 
 // Service socket
 //
 
-fn create_service_socket(id: u32) -> Result<Arc<Mutex<zmq::Socket>>> {
-    let socket_id = format!("W{}", id);
+fn create_service_socket(id: u32, endpoint: &str) -> Result<Arc<Mutex<zmq::Socket>>> {
+    let service_socket = open_service_socket(id, endpoint)?;
+    let guard = Arc::new(Mutex::new(service_socket));
+    Ok(guard)
+}
+
+fn reconnect_service_socket(
+    service_socket_guard: Arc<Mutex<zmq::Socket>>,
+    id: u32,
+    endpoint: &str,
+) -> Result<()> {
+    let mut service_socket = service_socket_guard
+        .lock()
+        .expect(MSG_FAILED_TO_ACQUIRE_LOCK_OF_SERVICE_SOCKET);
+    *service_socket = open_service_socket(id, endpoint)?;
+    Ok(())
+}
+
+fn open_service_socket(id: u32, endpoint: &str) -> Result<zmq::Socket> {
+    // workers might join from many hosts, so PID alone is not unique
+    let socket_id = format!("W{}-{}", get_hostname(), id);
     let context = zmq::Context::new();
-    let service_socket = context.socket(zmq::REQ).unwrap();
+    // DEALER rather than REQ, so heartbeats can go out at any time; thus the
+    // <EMPTY> delimiter REQ would add is handled by hand
+    let service_socket = context.socket(zmq::DEALER).unwrap();
     service_socket.set_identity(socket_id.as_bytes())?;
+    service_socket.set_linger(0)?;
     service_socket.set_sndtimeo(1000)?;
-    service_socket.set_rcvtimeo(1000)?;
     service_socket
-        .connect("tcp://127.0.0.1:6661")
-        .expect("failed listening on port 6661");
-    let guard = Arc::new(Mutex::new(service_socket));
-    Ok(guard)
+        .connect(endpoint)
+        .expect(format!("failed connecting to {}", endpoint).as_str());
+    Ok(service_socket)
 }
-fn finish_service_socket(service_socket_guard: Arc<Mutex<zmq::Socket>>) {
+fn finish_service_socket(service_socket_guard: Arc<Mutex<zmq::Socket>>, endpoint: &str) {
     let service_socket = service_socket_guard
         .lock()
         .expect(MSG_FAILED_TO_ACQUIRE_LOCK_OF_SERVICE_SOCKET);
     service_socket
-        .disconnect("tcp://127.0.0.1:6661")
-        .expect("failed disconnecting on port 6661");
+        .disconnect(endpoint)
+        .expect(format!("failed disconnecting from {}", endpoint).as_str());
 }
 
 fn send_messsage(
     service_socket_guard: Arc<Mutex<zmq::Socket>>,
-    message: &str,
+    message: &[&str],
     expect_message: &str,
 ) {
     let service_socket = service_socket_guard
         .lock()
         .expect(MSG_FAILED_TO_ACQUIRE_LOCK_OF_SERVICE_SOCKET);
-    send(&service_socket, message, expect_message);
+    // multipart envelope to broker as:
+    //   EMPTY, MESSAGE
+    //   EMPTY, MESSAGE, DETAILS
+    let mut envelope = vec![b"".to_vec()];
+    envelope.extend(message.iter().map(|frame| frame.as_bytes().to_vec()));
+    send_multipart(&service_socket, envelope, expect_message);
 }
 
-fn recv_message(
+fn send_heartbeat(service_socket_guard: Arc<Mutex<zmq::Socket>>) -> bool {
+    let service_socket = service_socket_guard
+        .lock()
+        .expect(MSG_FAILED_TO_ACQUIRE_LOCK_OF_SERVICE_SOCKET);
+    let envelope = vec![b"".to_vec(), MSG_HEARTBEAT.as_bytes().to_vec()];
+    service_socket
+        .send_multipart(envelope, zmq::DONTWAIT)
+        .is_ok()
+}
+
+// Nothing from broker until the timeout counts as one missed heartbeat. Only
+// the event loop waits on the socket, and only while idle, so nobody else is
+// kept waiting for the lock meanwhile.
+fn recv_frames(
     service_socket_guard: Arc<Mutex<zmq::Socket>>,
-) -> std::result::Result<std::result::Result<String, Vec<u8>>, zmq::Error> {
+    timeout: Duration,
+) -> std::result::Result<Option<Vec<Vec<u8>>>, zmq::Error> {
+    let service_socket = service_socket_guard
+        .lock()
+        .expect(MSG_FAILED_TO_ACQUIRE_LOCK_OF_SERVICE_SOCKET);
+    let mut poll_items = [service_socket.as_poll_item(zmq::POLLIN)];
+    zmq::poll(&mut poll_items, timeout.as_millis() as i64)?;
+    if !poll_items[0].is_readable() {
+        return Ok(None);
+    }
+    service_socket.recv_multipart(0).map(Some)
+}
+
+// Exiting skips destructors, so a temp directory of the render is removed by
+// hand before.
+fn remove_workdir(id: u32, workdir: Option<PathBuf>) {
+    if let Some(path) = workdir {
+        if let Err(reason) = fs::remove_dir_all(&path) {
+            println!("[#{}] Failed to remove {}: {}", id, path.display(), reason);
+        }
+    }
+}
+
+// Whether broker cancelled the render in progress, read while the event loop
+// is busy rendering; anything else broker sends a busy worker is a heartbeat.
+fn recv_cancel(service_socket_guard: Arc<Mutex<zmq::Socket>>, rendering: &AtomicBool) -> bool {
     let service_socket = service_socket_guard
         .lock()
         .expect(MSG_FAILED_TO_ACQUIRE_LOCK_OF_SERVICE_SOCKET);
-    service_socket.recv_string(0)
+    while rendering.load(Ordering::SeqCst) {
+        match service_socket.recv_multipart(zmq::DONTWAIT) {
+            Ok(frames) => {
+                if frames.len() == 2 && frames[1] == MSG_CANCEL.as_bytes() {
+                    return true;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    false
 }
 
 fn send_client_reply_with_success(
     service_socket_guard: Arc<Mutex<zmq::Socket>>,
-    client_id: &String,
-    content: &String,
+    client_id: &Identity,
+    reply: &RenderReply,
+    attachment: Option<Vec<u8>>,
 ) {
-    send_client_reply(
-        service_socket_guard.clone(),
-        &client_id,
-        REP_200_SUCCESS,
-        &content,
-    );
+    send_client_reply(service_socket_guard.clone(), &client_id, &reply, attachment);
 }
 
-fn send_client_reply_with_error(
+fn send_client_reply_with_failure(
     service_socket_guard: Arc<Mutex<zmq::Socket>>,
-    client_id: &String,
-    err_code: &str,
-    err_msg: &String,
+    client_id: &Identity,
+    failure: &Failure,
 ) {
-    send_client_reply(
-        service_socket_guard.clone(),
-        &client_id,
-        &err_code,
-        &err_msg,
-    );
+    let mut reply = RenderReply::failure(&failure.reason, failure.stage);
+    reply.diagnostics = failure.diagnostics.clone();
+    send_client_reply(service_socket_guard.clone(), &client_id, &reply, None);
 }
 
 fn send_client_reply(
     service_socket_guard: Arc<Mutex<zmq::Socket>>,
-    client_id: &String,
-    reply_type: &str,
-    reply_content: &String,
+    client_id: &Identity,
+    reply: &RenderReply,
+    reply_attachment: Option<Vec<u8>>,
 ) {
     let service_socket = service_socket_guard
         .lock()
         .expect(MSG_FAILED_TO_ACQUIRE_LOCK_OF_SERVICE_SOCKET);
     // build reply multipart envelope to client as:
-    //   CLIENT, EMPTY, REPLY|ERROR, EMPTY, CONTENT
-    let reply_envelope = vec![
+    //   EMPTY, CLIENT, EMPTY, REPLY|ERROR, EMPTY, CONTENT
+    //   EMPTY, CLIENT, EMPTY, REPLY, EMPTY, CONTENT, PDF
+    // where CONTENT is a JSON encoded RenderReply
+    let mut reply_envelope = vec![
+        b"".to_vec(),
         client_id.as_bytes().to_vec(),
         b"".to_vec(),
-        reply_type.as_bytes().to_vec(),
+        reply.code.as_bytes().to_vec(),
         b"".to_vec(),
-        reply_content.as_bytes().to_vec(),
+        reply.to_json().into_bytes(),
     ];
+    if let Some(attachment) = reply_attachment {
+        reply_envelope.push(attachment);
+    }
 
     send_multipart(
         &service_socket,
@@ -467,10 +847,13 @@ mod tests {
         let worker = Worker::new(
             123,
             Arc::new(AtomicBool::new(false)),
+            "ipc:///tmp/wk-backend",
             Path::new("out"),
             Duration::from_secs(3),
+            HEARTBEAT_INTERVAL,
         );
         assert_eq!(worker.id, 123);
+        assert_eq!(worker.broker_endpoint, "ipc:///tmp/wk-backend");
         assert_eq!(worker.output_dir.as_os_str(), "out");
     }
 